@@ -1,318 +1,1647 @@
 use {
     crate::{
+        cache::Cache,
         config::Config,
+        endpoints::{Endpoints, Role},
+        fetch::{self, Throttle},
+        owners::OwnerMetadata,
         report::Report,
-        token::{DelegateBurn, DelegateChange, DelegateTransfer, OwnerChange, TokenAccountEntry},
+        token::{
+            CloseAuthorityChange, DelegateBurn, DelegateChange, DelegateRevoke, DelegateTransfer,
+            FreezeChange, IncompleteAccount, IncompleteOwner, MultisigConfig, OutboundReassignment,
+            OwnerChange, TokenAccountEntry, UnknownInstruction,
+        },
+    },
+    solana_client::rpc_config::RpcBlockConfig,
+    solana_sdk::{
+        clock::Slot, instruction::CompiledInstruction, program_option::COption, program_pack::Pack,
+        pubkey::Pubkey, signature::Signature, signer::Signer,
     },
-    solana_client::rpc_client::GetConfirmedSignaturesForAddress2Config,
-    solana_sdk::{clock::Slot, pubkey::Pubkey, signature::Signature, signer::Signer},
     solana_transaction_status::{
-        EncodedTransaction, EncodedTransactionWithStatusMeta, UiInstruction, UiMessage,
-        UiParsedInstruction, UiTransactionEncoding,
+        parse_token::parse_token, EncodedConfirmedTransaction, EncodedTransactionWithStatusMeta,
+        TransactionDetails, UiInnerInstructions, UiInstruction, UiTransactionStatusMeta,
+        UiTransactionTokenBalance,
+    },
+    spl_associated_token_account::get_associated_token_address,
+    spl_token::{
+        instruction::{AuthorityType, TokenInstruction},
+        state::{Mint, Multisig},
+    },
+    std::{
+        collections::{BTreeMap, HashMap, HashSet},
+        str::FromStr,
     },
-    std::str::FromStr,
 };
 
-fn get_as_pubkey(json_value: &serde_json::Value, field_name: &str) -> Pubkey {
-    Pubkey::from_str(json_value.get(field_name).unwrap().as_str().unwrap()).unwrap()
+const CONSUMED: bool = false;
+const IGNORED: bool = false;
+// accounts whose signatures are fetched at once, per worker
+const TARGETS_PER_WORKER: usize = 4;
+// transactions fetched before they're consumed
+const TRANSACTIONS_PER_PAGE: usize = 1000;
+
+fn instruction_type(token_instruction: &TokenInstruction) -> &'static str {
+    match token_instruction {
+        TokenInstruction::InitializeMint { .. } => "initializeMint",
+        TokenInstruction::InitializeAccount => "initializeAccount",
+        TokenInstruction::InitializeMultisig { .. } => "initializeMultisig",
+        TokenInstruction::Transfer { .. } => "transfer",
+        TokenInstruction::Approve { .. } => "approve",
+        TokenInstruction::Revoke => "revoke",
+        TokenInstruction::SetAuthority { .. } => "setAuthority",
+        TokenInstruction::MintTo { .. } => "mintTo",
+        TokenInstruction::Burn { .. } => "burn",
+        TokenInstruction::CloseAccount => "closeAccount",
+        TokenInstruction::FreezeAccount => "freezeAccount",
+        TokenInstruction::ThawAccount => "thawAccount",
+        TokenInstruction::TransferChecked { .. } => "transferChecked",
+        TokenInstruction::ApproveChecked { .. } => "approveChecked",
+        TokenInstruction::MintToChecked { .. } => "mintToChecked",
+        TokenInstruction::BurnChecked { .. } => "burnChecked",
+        TokenInstruction::InitializeAccount2 { .. } => "initializeAccount2",
+        TokenInstruction::SyncNative => "syncNative",
+    }
 }
 
-fn try_to_recognize_and_consume_ix(
-    current_owner: Pubkey,
+fn decimals(token_instruction: &TokenInstruction) -> Option<u8> {
+    match token_instruction {
+        TokenInstruction::TransferChecked { decimals, .. }
+        | TokenInstruction::ApproveChecked { decimals, .. }
+        | TokenInstruction::MintToChecked { decimals, .. }
+        | TokenInstruction::BurnChecked { decimals, .. } => Some(*decimals),
+        _ => None,
+    }
+}
+
+fn format_original_ix(ix: &serde_json::Value) -> String {
+    let original_ix = format!("{}", ix).replace("\"", "\"\"");
+    format!("\"{}\"", original_ix)
+}
+
+// with binary encodings, inner instructions are returned compiled just like the message's
+fn decode_inner_instruction(ix: UiInstruction) -> Option<CompiledInstruction> {
+    match ix {
+        UiInstruction::Compiled(ix) => Some(CompiledInstruction {
+            program_id_index: ix.program_id_index,
+            accounts: ix.accounts,
+            data: bs58::decode(ix.data).into_vec().ok()?,
+        }),
+        UiInstruction::Parsed(_) => None,
+    }
+}
+
+/// Top-level and inner instructions of a transaction in execution order, each
+/// with the index of its top-level instruction and, for inner instructions, the
+/// program which invoked it
+///
+/// Inner instructions are executed right after their top-level instruction.
+/// The ones which can't be decoded are kept as `None`.
+fn flatten_instructions(
+    instructions: Vec<CompiledInstruction>,
+    mut inner_instructions: Vec<UiInnerInstructions>,
+    account_keys: &[Pubkey],
+) -> Vec<(usize, Option<Pubkey>, Option<CompiledInstruction>)> {
+    let mut flattened = Vec::new();
+    for (index, ix) in instructions.into_iter().enumerate() {
+        let outer_program_id = account_keys.get(ix.program_id_index as usize).copied();
+        flattened.push((index, None, Some(ix)));
+        for inner in inner_instructions
+            .iter_mut()
+            .filter(|inner| inner.index as usize == index)
+        {
+            flattened.extend(
+                inner
+                    .instructions
+                    .drain(..)
+                    .map(|inner_ix| (index, outer_program_id, decode_inner_instruction(inner_ix))),
+            );
+        }
+    }
+    flattened
+}
+
+/// An SPL Token instruction decoded locally against the message account keys
+struct DecodedInstruction {
+    instruction_index: usize,
+    outer_instruction_index: usize,
+    // `None` for top-level instructions
+    outer_program_id: Option<Pubkey>,
+    // `None` if the instruction data or accounts can't be decoded
+    token_instruction: Option<TokenInstruction>,
+    accounts: Vec<Pubkey>,
+    original_ix: String,
+}
+
+impl DecodedInstruction {
+    fn decode(
+        instruction_index: usize,
+        outer_instruction_index: usize,
+        outer_program_id: Option<Pubkey>,
+        instruction: &CompiledInstruction,
+        account_keys: &[Pubkey],
+    ) -> Self {
+        let accounts = instruction
+            .accounts
+            .iter()
+            .map(|index| account_keys.get(*index as usize).copied())
+            .collect::<Option<Vec<_>>>();
+        let token_instruction = accounts
+            .as_ref()
+            .and_then(|_| TokenInstruction::unpack(&instruction.data).ok());
+        // render the same json as RPC nodes do for jsonParsed, falling back to the raw instruction
+        let original_ix = parse_token(instruction, account_keys)
+            .ok()
+            .and_then(|parsed| serde_json::to_value(parsed).ok())
+            .unwrap_or_else(|| {
+                serde_json::json!({
+                    "accounts": instruction.accounts,
+                    "data": bs58::encode(&instruction.data).into_string(),
+                })
+            });
+        Self {
+            instruction_index,
+            outer_instruction_index,
+            outer_program_id,
+            token_instruction,
+            accounts: accounts.unwrap_or_default(),
+            original_ix: format_original_ix(&original_ix),
+        }
+    }
+
+    fn account(&self, index: usize) -> Option<Pubkey> {
+        self.accounts.get(index).copied()
+    }
+
+    fn authority_index(&self) -> Option<usize> {
+        match self.token_instruction.as_ref()? {
+            TokenInstruction::Revoke | TokenInstruction::SetAuthority { .. } => Some(1),
+            TokenInstruction::Transfer { .. }
+            | TokenInstruction::Approve { .. }
+            | TokenInstruction::MintTo { .. }
+            | TokenInstruction::MintToChecked { .. }
+            | TokenInstruction::Burn { .. }
+            | TokenInstruction::BurnChecked { .. }
+            | TokenInstruction::CloseAccount
+            | TokenInstruction::FreezeAccount
+            | TokenInstruction::ThawAccount => Some(2),
+            TokenInstruction::TransferChecked { .. } | TokenInstruction::ApproveChecked { .. } => {
+                Some(3)
+            }
+            TokenInstruction::InitializeMint { .. }
+            | TokenInstruction::InitializeAccount
+            | TokenInstruction::InitializeMultisig { .. }
+            | TokenInstruction::InitializeAccount2 { .. }
+            | TokenInstruction::SyncNative => None,
+        }
+    }
+
+    /// The token account which this instruction shows the wallet was
+    /// involved with, as its owner, its new owner or the receiver of its
+    /// lamports on close
+    fn historical_token_account(&self, wallet: &Pubkey) -> Option<Pubkey> {
+        let is_wallet = |index| self.account(index).as_ref() == Some(wallet);
+        let involved = match self.token_instruction.as_ref()? {
+            TokenInstruction::InitializeAccount => is_wallet(2),
+            TokenInstruction::InitializeAccount2 { owner } => owner == wallet,
+            TokenInstruction::SetAuthority {
+                authority_type: AuthorityType::AccountOwner,
+                new_authority,
+            } => *new_authority == COption::Some(*wallet) || is_wallet(1),
+            TokenInstruction::SetAuthority {
+                authority_type: AuthorityType::CloseAccount,
+                ..
+            } => is_wallet(1),
+            TokenInstruction::CloseAccount => is_wallet(1) || is_wallet(2),
+            // only the owner can approve and revoke
+            TokenInstruction::Approve { .. }
+            | TokenInstruction::ApproveChecked { .. }
+            | TokenInstruction::Revoke => self.authority_index().map(is_wallet).unwrap_or(false),
+            _ => false,
+        };
+        if involved {
+            self.account(0)
+        } else {
+            None
+        }
+    }
+
+    /// The token account and its new owner, if this instruction is the wallet
+    /// handing its account to someone else
+    fn outbound_reassignment(&self, wallet: &Pubkey) -> Option<(Pubkey, Pubkey)> {
+        match self.token_instruction.as_ref()? {
+            TokenInstruction::SetAuthority {
+                authority_type: AuthorityType::AccountOwner,
+                new_authority: COption::Some(new_owner),
+            } if self.account(1).as_ref() == Some(wallet) && new_owner != wallet => {
+                Some((self.account(0)?, *new_owner))
+            }
+            _ => None,
+        }
+    }
+
+    /// The multisig address and its signing members, if the authority of this
+    /// instruction is an spl-token multisig
+    fn multisig_authority(&self) -> Option<(Pubkey, &[Pubkey])> {
+        let authority_index = self.authority_index()?;
+        let multisig_signers = self.accounts.get(authority_index + 1..)?;
+        if multisig_signers.is_empty() {
+            None
+        } else {
+            Some((self.account(authority_index)?, multisig_signers))
+        }
+    }
+
+    fn multisig_signers(&self) -> Vec<Pubkey> {
+        self.multisig_authority()
+            .map(|(_multisig, multisig_signers)| multisig_signers.to_vec())
+            .unwrap_or_default()
+    }
+}
+
+/// Mint of the account at the given index, from the token balances in the
+/// status meta of a transaction
+fn token_balance_mint(meta: &UiTransactionStatusMeta, account_index: usize) -> Option<Pubkey> {
+    meta.pre_token_balances
+        .iter()
+        .chain(meta.post_token_balances.iter())
+        .flatten()
+        .find(|token_balance| token_balance.account_index as usize == account_index)
+        .and_then(|token_balance| Pubkey::from_str(&token_balance.mint).ok())
+}
+
+/// What the transaction history of a wallet shows about token accounts
+#[derive(Default)]
+struct WalletHistory {
+    // every token account the wallet was involved with, along with its mint if it can be found
+    token_accounts: BTreeMap<Pubkey, Option<Pubkey>>,
+    // the accounts the wallet handed to other owners, oldest first
+    outbound_reassignments: Vec<OutboundReassignment>,
+}
+
+/// Scan the transaction history of the wallet for its token accounts
+///
+/// This finds the accounts which the wallet doesn't own anymore, either because
+/// they were reassigned away or closed.
+fn wallet_history(
+    endpoints: &Endpoints,
+    cache: Option<&Cache>,
+    throttle: &Throttle,
+    concurrency: usize,
+    wallet: &Pubkey,
+) -> Result<WalletHistory, String> {
+    let mut history = WalletHistory::default();
+    let (sigs, _urls) = fetch::get_all_signatures(endpoints, cache, throttle, wallet)
+        .map_err(|err| format!("couldn't fetch the signatures of {}: {}", wallet, err))?;
+    let sigs = fetch::successful_signatures(&sigs).collect::<Vec<_>>();
+    // a page at a time, so that long histories aren't all held in memory
+    for page in sigs.chunks(TRANSACTIONS_PER_PAGE) {
+        let page_signatures = page.iter().map(|(sig, _slot)| *sig).collect::<Vec<_>>();
+        let confirmations =
+            fetch::get_transactions(endpoints, cache, throttle, concurrency, &page_signatures);
+        for (&(sig, slot), confirmation) in page.iter().zip(confirmations) {
+            let (confirmation, _url) = confirmation
+                .map_err(|err| format!("couldn't fetch transaction {}: {}", sig, err))?;
+            let EncodedTransactionWithStatusMeta { transaction, meta } = confirmation.transaction;
+            let transaction = if let Some(transaction) = transaction.decode() {
+                transaction
+            } else {
+                eprintln!("undecodable transaction {} for {}", sig, wallet);
+                continue;
+            };
+            let account_keys = transaction.message.account_keys;
+            let inner_instructions = meta
+                .as_ref()
+                .and_then(|meta| meta.inner_instructions.clone())
+                .unwrap_or_default();
+            let instructions = flatten_instructions(
+                transaction.message.instructions,
+                inner_instructions,
+                &account_keys,
+            );
+            for (instruction_index, (outer_instruction_index, outer_program_id, ix)) in
+                instructions.into_iter().enumerate()
+            {
+                let instruction = match ix {
+                    Some(instruction) => instruction,
+                    None => continue,
+                };
+                if account_keys.get(instruction.program_id_index as usize) != Some(&spl_token::id())
+                {
+                    continue;
+                }
+                let ix = DecodedInstruction::decode(
+                    instruction_index,
+                    outer_instruction_index,
+                    outer_program_id,
+                    &instruction,
+                    &account_keys,
+                );
+                if let Some((token_address, new_owner)) = ix.outbound_reassignment(wallet) {
+                    history.outbound_reassignments.push(OutboundReassignment {
+                        token_address,
+                        previous_owner: *wallet,
+                        new_owner,
+                        slot,
+                        transaction_id: sig,
+                        owner_metadata: vec![],
+                    });
+                }
+                let token_address = match ix.historical_token_account(wallet) {
+                    Some(token_address) => token_address,
+                    None => continue,
+                };
+                let mint = match ix.token_instruction {
+                    Some(TokenInstruction::InitializeAccount)
+                    | Some(TokenInstruction::InitializeAccount2 { .. }) => ix.account(1),
+                    _ => meta.as_ref().and_then(|meta| {
+                        let account_index =
+                            account_keys.iter().position(|key| *key == token_address)?;
+                        token_balance_mint(meta, account_index)
+                    }),
+                };
+                let entry = history.token_accounts.entry(token_address).or_default();
+                if entry.is_none() {
+                    *entry = mint;
+                }
+            }
+        }
+    }
+    // the signatures come newest first
+    history.outbound_reassignments.reverse();
+    Ok(history)
+}
+
+/// Raw change of the balance of the account at the given index over a
+/// transaction, from the token balances in its status meta
+///
+/// An account missing from one side was created or closed in the transaction,
+/// so its balance counts as zero there.
+fn token_balance_change(meta: &UiTransactionStatusMeta, account_index: usize) -> Option<i128> {
+    let balance = |token_balances: &Option<Vec<UiTransactionTokenBalance>>| {
+        token_balances.as_ref().map(|token_balances| {
+            token_balances
+                .iter()
+                .find(|token_balance| token_balance.account_index as usize == account_index)
+                .and_then(|token_balance| token_balance.ui_token_amount.amount.parse::<u64>().ok())
+        })
+    };
+    match (
+        balance(&meta.pre_token_balances)?,
+        balance(&meta.post_token_balances)?,
+    ) {
+        (None, None) => None,
+        (pre, post) => Some(post.unwrap_or(0) as i128 - pre.unwrap_or(0) as i128),
+    }
+}
+
+fn get_mint_decimals(endpoints: &Endpoints, mint: &Pubkey) -> Option<u8> {
+    let account = endpoints
+        .call(Role::Accounts, |rpc_client| rpc_client.get_account(mint))
+        .ok()?;
+    Some(Mint::unpack(&account.data).ok()?.decimals)
+}
+
+fn get_multisig_config(
+    endpoints: &Endpoints,
+    throttle: &Throttle,
+    multisig: &Pubkey,
+) -> Option<MultisigConfig> {
+    let (account, _url) = throttle
+        .call(|| {
+            endpoints.try_each(Role::Accounts, |rpc_client| {
+                rpc_client.get_account(multisig)
+            })
+        })
+        .ok()?;
+    if account.owner != spl_token::id() {
+        return None;
+    }
+    let multisig = Multisig::unpack(&account.data).ok()?;
+    Some(MultisigConfig {
+        m: multisig.m,
+        n: multisig.n,
+        members: multisig.signers[..multisig.n as usize].to_vec(),
+    })
+}
+
+/// Returns `None` if the instruction doesn't have the accounts expected for its type
+///
+/// The transaction index of recorded events is left at 0, it's only resolved
+/// from the block later if other transactions in the same slot have events too
+fn consume_ix(
     reported_token_address: Pubkey,
-    _reported_token_mint: Pubkey,
     token_account_entry: &mut TokenAccountEntry,
     slot: Slot,
     sig: Signature,
-    ix: &serde_json::Value,
-) -> bool {
-    const CONSUMED: bool = false;
-    const IGNORED: bool = false;
-
-    match ix.get("type").as_ref() {
-        Some(serde_json::value::Value::String(ix_type)) => {
-            let ix_info = ix.get("info");
-            match ix_type.as_ref() {
-                "transfer" | "transferChecked" => {
-                    let source_address = get_as_pubkey(ix_info.unwrap(), "source");
-                    let destination_address = get_as_pubkey(ix_info.unwrap(), "destination");
-                    if source_address != reported_token_address
-                        && destination_address != reported_token_address
-                    {
-                        // irrelevant transfer instruction (ixes can be mixed arbitrarily)
-                        return IGNORED;
-                    }
+    ix: &DecodedInstruction,
+) -> Option<bool> {
+    let token_instruction = ix.token_instruction.as_ref()?;
+    let instruction_index = ix.instruction_index;
+    let decimals = decimals(token_instruction).unwrap_or(token_account_entry.decimals);
+    let consumed = match *token_instruction {
+        TokenInstruction::Transfer { amount }
+        | TokenInstruction::TransferChecked { amount, .. } => {
+            // checked transfers have the mint in between
+            let (source_address, destination_address, signer) = match token_instruction {
+                TokenInstruction::Transfer { .. } => {
+                    (ix.account(0)?, ix.account(1)?, ix.account(2)?)
+                }
+                _ => (ix.account(0)?, ix.account(2)?, ix.account(3)?),
+            };
+            if source_address != reported_token_address
+                && destination_address != reported_token_address
+            {
+                // irrelevant transfer instruction (ixes can be mixed arbitrarily)
+                return Some(IGNORED);
+            }
 
-                    if source_address != reported_token_address {
-                        assert_eq!(destination_address, reported_token_address);
-                        // transfer incoming into reported_token_address isn't harmful
-                        return IGNORED;
-                    }
+            if source_address != reported_token_address {
+                assert_eq!(destination_address, reported_token_address);
+                // transfer incoming into reported_token_address isn't harmful
+                return Some(IGNORED);
+            }
 
-                    let signer = get_as_pubkey(ix_info.unwrap(), "authority");
-                    // anything signed off by current owner isn't harmful
-                    if signer == current_owner {
-                        return IGNORED;
-                    }
+            // anything signed off by current owner isn't harmful
+            if token_account_entry.is_current_owner(&signer) {
+                return Some(IGNORED);
+            }
 
-                    // unrelated mint; well no-checked ixes doens't have mint....
-                    //dbg!(&ix);
-                    //let mint = get_as_pubkey(ix_info.unwrap(), "mint");
-                    //if mint != reported_token_mint {
-                    //    return IGNORED;
-                    //}
-
-                    let original_ix = format!("{}", ix).replace("\"", "\"\"");
-                    let original_ix = format!("\"{}\"", original_ix);
-                    token_account_entry
-                        .possible_delegate_transfers
-                        .push(DelegateTransfer {
-                            slot,
-                            transaction_id: sig,
-                            signer,
-                            // TODO: todo: properly handle this field!
-                            amount: format!(
-                                "{}",
-                                ix_info
-                                    .unwrap()
-                                    .get("tokenAmount")
-                                    .map(|ta| ta.get("uiAmountString").unwrap())
-                                    .unwrap_or_else(|| ix_info.unwrap().get("amount").unwrap())
-                            ),
-                            original_ix,
-                        });
-                    CONSUMED
-                }
-                "burn" | "burnChecked" => {
-                    let token_address = get_as_pubkey(ix_info.unwrap(), "account");
-                    if token_address != reported_token_address {
-                        // unrelated burns
-                        return IGNORED;
-                    }
+            token_account_entry
+                .possible_delegate_transfers
+                .push(DelegateTransfer {
+                    slot,
+                    transaction_index: 0,
+                    instruction_index,
+                    outer_instruction_index: ix.outer_instruction_index,
+                    outer_program_id: ix.outer_program_id,
+                    transaction_id: sig,
+                    signer,
+                    multisig_signers: ix.multisig_signers(),
+                    amount,
+                    decimals,
+                    // filled in once the whole transaction has been consumed
+                    balance_change: None,
+                    original_ix: ix.original_ix.clone(),
+                });
+            CONSUMED
+        }
+        TokenInstruction::Burn { amount } | TokenInstruction::BurnChecked { amount, .. } => {
+            let token_address = ix.account(0)?;
+            if token_address != reported_token_address {
+                // unrelated burns
+                return Some(IGNORED);
+            }
 
-                    let original_ix = format!("{}", ix).replace("\"", "\"\"");
-                    let original_ix = format!("\"{}\"", original_ix);
-                    token_account_entry
-                        .possible_delegate_burns
-                        .push(DelegateBurn {
-                            slot,
-                            transaction_id: sig,
-                            signer: get_as_pubkey(ix_info.unwrap(), "authority"),
-                            // TODO: todo: properly handle this field!
-                            amount: format!(
-                                "{}",
-                                ix_info
-                                    .unwrap()
-                                    .get("tokenAmount")
-                                    .map(|ta| ta.get("uiAmountString").unwrap())
-                                    .unwrap_or_else(|| ix_info.unwrap().get("amount").unwrap())
-                            ),
-                            original_ix,
-                        });
-                    CONSUMED
+            token_account_entry
+                .possible_delegate_burns
+                .push(DelegateBurn {
+                    slot,
+                    transaction_index: 0,
+                    instruction_index,
+                    outer_instruction_index: ix.outer_instruction_index,
+                    outer_program_id: ix.outer_program_id,
+                    transaction_id: sig,
+                    signer: ix.account(2)?,
+                    multisig_signers: ix.multisig_signers(),
+                    amount,
+                    decimals,
+                    balance_change: None,
+                    original_ix: ix.original_ix.clone(),
+                });
+            CONSUMED
+        }
+        TokenInstruction::Approve { amount } | TokenInstruction::ApproveChecked { amount, .. } => {
+            let token_address = ix.account(0)?;
+            if token_address != reported_token_address {
+                // unrelated approvals
+                return Some(IGNORED);
+            }
+
+            // checked approvals have the mint in between
+            let (new_delegate, signer) = match token_instruction {
+                TokenInstruction::Approve { .. } => (ix.account(1)?, ix.account(2)?),
+                _ => (ix.account(2)?, ix.account(3)?),
+            };
+            // approvals signed off by current owner are kept too, because they replace any
+            // previous delegation
+            token_account_entry.delegate_changes.push(DelegateChange {
+                slot,
+                transaction_index: 0,
+                instruction_index,
+                outer_instruction_index: ix.outer_instruction_index,
+                outer_program_id: ix.outer_program_id,
+                transaction_id: sig,
+                signer,
+                multisig_signers: ix.multisig_signers(),
+                new_delegate,
+                amount,
+                decimals,
+                original_ix: ix.original_ix.clone(),
+            });
+            CONSUMED
+        }
+        TokenInstruction::Revoke => {
+            let token_address = ix.account(0)?;
+            if token_address != reported_token_address {
+                // unrelated revokes
+                return Some(IGNORED);
+            }
+
+            token_account_entry.delegate_revokes.push(DelegateRevoke {
+                slot,
+                transaction_index: 0,
+                instruction_index,
+                outer_instruction_index: ix.outer_instruction_index,
+                outer_program_id: ix.outer_program_id,
+                transaction_id: sig,
+                signer: ix.account(1)?,
+                multisig_signers: ix.multisig_signers(),
+                original_ix: ix.original_ix.clone(),
+            });
+            CONSUMED
+        }
+        TokenInstruction::SetAuthority {
+            ref authority_type,
+            new_authority,
+        } => match authority_type {
+            AuthorityType::AccountOwner => {
+                // owner changes signed off by current owner are kept too, because they're needed
+                // to build the chain of owners
+                let signer = ix.account(1)?;
+                let token_address = ix.account(0)?;
+                if token_address != reported_token_address {
+                    // unrelated authorizations
+                    return Some(IGNORED);
                 }
-                "approve" | "approveChecked" => {
-                    let signer = get_as_pubkey(ix_info.unwrap(), "owner");
-                    // anything signed off by current owner isn't harmful
-                    if signer == current_owner {
-                        return IGNORED;
-                    }
 
-                    let token_address = get_as_pubkey(ix_info.unwrap(), "source");
-                    if token_address != reported_token_address {
-                        // unrelated approvals
-                        return IGNORED;
-                    }
+                let new_owner = match new_authority {
+                    COption::Some(new_owner) => new_owner,
+                    COption::None => return None,
+                };
+                token_account_entry.owner_changes.push(OwnerChange {
+                    slot,
+                    transaction_index: 0,
+                    instruction_index,
+                    outer_instruction_index: ix.outer_instruction_index,
+                    outer_program_id: ix.outer_program_id,
+                    transaction_id: sig,
+                    new_owner,
+                    signer,
+                    multisig_signers: ix.multisig_signers(),
+                    original_ix: ix.original_ix.clone(),
+                });
+                CONSUMED
+            }
+            AuthorityType::CloseAccount => {
+                let token_address = ix.account(0)?;
+                if token_address != reported_token_address {
+                    // unrelated authorizations
+                    return Some(IGNORED);
+                }
 
-                    let original_ix = format!("{}", ix).replace("\"", "\"\"");
-                    let original_ix = format!("\"{}\"", original_ix);
-                    let new_delegate = get_as_pubkey(ix_info.unwrap(), "delegate");
-                    token_account_entry
-                        .all_delegate_addresses
-                        .insert(new_delegate);
-                    token_account_entry.delegate_changes.push(DelegateChange {
+                token_account_entry
+                    .close_authority_changes
+                    .push(CloseAuthorityChange {
                         slot,
+                        transaction_index: 0,
+                        instruction_index,
+                        outer_instruction_index: ix.outer_instruction_index,
+                        outer_program_id: ix.outer_program_id,
                         transaction_id: sig,
-                        signer,
-                        new_delegate,
-                        original_ix,
+                        signer: ix.account(1)?,
+                        multisig_signers: ix.multisig_signers(),
+                        new_close_authority: new_authority.into(),
+                        original_ix: ix.original_ix.clone(),
                     });
-                    CONSUMED
-                }
-                "setAuthority" => {
-                    match (ix_info.map(|info| info.get("authorityType").unwrap())).as_ref() {
-                        Some(serde_json::value::Value::String(authority_type)) => {
-                            match authority_type.as_ref() {
-                                "accountOwner" => {
-                                    let signer = get_as_pubkey(ix_info.unwrap(), "authority");
-                                    // anything signed off by current owner isn't harmful
-                                    if signer == current_owner {
-                                        return IGNORED;
-                                    }
-
-                                    let token_address = get_as_pubkey(ix_info.unwrap(), "account");
-                                    if token_address != reported_token_address {
-                                        // unrelated authorizations
-                                        return IGNORED;
-                                    }
-
-                                    let original_ix = format!("{}", ix).replace("\"", "\"\"");
-                                    let original_ix = format!("\"{}\"", original_ix);
-                                    token_account_entry.owner_changes.push(OwnerChange {
-                                        slot,
-                                        transaction_id: sig,
-                                        new_owner: get_as_pubkey(ix_info.unwrap(), "newAuthority"),
-                                        signer,
-                                        original_ix,
-                                    });
-                                    CONSUMED
-                                }
-                                "closeAccount" => CONSUMED,
-                                _ => !CONSUMED,
-                            }
-                        }
-                        _ => !CONSUMED,
-                    }
-                }
-                "initializeAccount" | "closeAccount" => IGNORED,
-                "mintTo" | "mintToChecked" => IGNORED,
-                "revoke" => IGNORED, // TODO: this could be useful to create a nicely-looking post-incident report?
-                _ => !CONSUMED,
+                CONSUMED
+            }
+            // these are authorities of mints, not of token accounts
+            AuthorityType::MintTokens | AuthorityType::FreezeAccount => IGNORED,
+        },
+        TokenInstruction::FreezeAccount | TokenInstruction::ThawAccount => {
+            let token_address = ix.account(0)?;
+            if token_address != reported_token_address {
+                // unrelated freezes
+                return Some(IGNORED);
             }
+
+            token_account_entry.freeze_changes.push(FreezeChange {
+                slot,
+                transaction_index: 0,
+                instruction_index,
+                outer_instruction_index: ix.outer_instruction_index,
+                outer_program_id: ix.outer_program_id,
+                transaction_id: sig,
+                signer: ix.account(2)?,
+                multisig_signers: ix.multisig_signers(),
+                frozen: matches!(token_instruction, TokenInstruction::FreezeAccount),
+                original_ix: ix.original_ix.clone(),
+            });
+            CONSUMED
+        }
+        TokenInstruction::InitializeMint { .. } | TokenInstruction::InitializeMultisig { .. } => {
+            IGNORED
         }
-        _ => !CONSUMED,
+        TokenInstruction::InitializeAccount
+        | TokenInstruction::InitializeAccount2 { .. }
+        | TokenInstruction::CloseAccount
+        | TokenInstruction::SyncNative => IGNORED,
+        TokenInstruction::MintTo { .. } | TokenInstruction::MintToChecked { .. } => IGNORED,
+    };
+    Some(consumed)
+}
+
+/// Returns `true` if the instruction couldn't be recognized
+fn try_to_recognize_and_consume_ix(
+    reported_token_address: Pubkey,
+    token_account_entry: &mut TokenAccountEntry,
+    slot: Slot,
+    sig: Signature,
+    ix: &DecodedInstruction,
+) -> bool {
+    let unrecognized =
+        consume_ix(reported_token_address, token_account_entry, slot, sig, ix).unwrap_or(!CONSUMED);
+    if let (false, Some(token_instruction)) = (unrecognized, &ix.token_instruction) {
+        *token_account_entry
+            .instruction_counts
+            .entry(instruction_type(token_instruction).to_string())
+            .or_default() += 1;
     }
+    unrecognized
 }
 
-pub fn run(config: Config, owners: Vec<Box<dyn Signer>>, mints: Option<Vec<Pubkey>>) {
-    println!("audit");
-    let mut report = Report::new();
-    const SIGNATURES_LIMIT: usize = 1000;
-    crate::for_all_spl_token_accounts(
-        &config,
-        owners.as_slice(),
-        mints.as_deref(),
-        |config, owner, reported_token_address, account| {
-            let rpc_client = &config.rpc_client;
-            let owner_pubkey = owner.pubkey();
-            let mut token_account_entry = report
-                .entries_by_token_address
-                //.entry((owner_pubkey, account.mint))
-                .entry(*reported_token_address)
-                .or_insert_with(|| TokenAccountEntry::new(owner_pubkey, account.mint));
-            let mut before = Option::<Signature>::None;
-            loop {
-                let request_config = GetConfirmedSignaturesForAddress2Config {
-                    before,
-                    limit: Some(SIGNATURES_LIMIT),
-                    ..GetConfirmedSignaturesForAddress2Config::default()
-                };
-                #[allow(deprecated)]
-                let sigs = rpc_client
-                    .get_confirmed_signatures_for_address2_with_config(
-                        reported_token_address,
-                        request_config,
-                    )
-                    .unwrap();
+/// Which token accounts to audit
+pub enum AuditScope {
+    /// The accounts of the owners, on the given mints or all of them
+    ///
+    /// With `historical`, token accounts found in the owners' own transaction
+    /// history are audited too, even if they were reassigned away or closed
+    /// since. With `outbound`, the accounts the owners themselves handed to
+    /// someone else are audited and reported, along with the new owners. The
+    /// owner metadata is carried into the report rows of their accounts.
+    Owners {
+        owners: Vec<Box<dyn Signer>>,
+        mints: Option<Vec<Pubkey>>,
+        historical: bool,
+        outbound: bool,
+        owner_metadata: OwnerMetadata,
+    },
+    /// Every account of the mint, whoever owns it, for token issuers
+    MintWide(Pubkey),
+    /// Just the given token accounts, under their current owners
+    Accounts(Vec<Pubkey>),
+}
 
-                before = if sigs.len() < SIGNATURES_LIMIT {
-                    None
-                } else {
-                    sigs.last()
-                        .and_then(|s| Signature::from_str(s.signature.as_str()).ok())
-                };
+// the current state of a token account given directly
+fn get_token_account(
+    endpoints: &Endpoints,
+    token_address: &Pubkey,
+) -> Result<spl_token::state::Account, String> {
+    let account = endpoints
+        .call(Role::Accounts, |rpc_client| {
+            rpc_client.get_account(token_address)
+        })
+        .map_err(|err| {
+            if fetch::is_transient(&err.kind) {
+                format!("couldn't fetch token account {}: {}", token_address, err)
+            } else {
+                format!("token account {} does not exist", token_address)
+            }
+        })?;
+    if account.owner != spl_token::id() {
+        return Err(format!(
+            "account {} is not owned by the SPL token program, actually owned by {}",
+            token_address, account.owner
+        ));
+    }
+    spl_token::state::Account::unpack(&account.data)
+        .map_err(|_| format!("account {} is not an SPL token account", token_address))
+}
 
-                // Exclude any transactions which failed
-                let total_sig_len = sigs.len();
-                token_account_entry.total_tx_count += total_sig_len;
-                let sigs = sigs.iter().filter_map(|sig_with_status| {
-                    if sig_with_status.err.is_some() {
-                        None
-                    } else {
-                        Signature::from_str(sig_with_status.signature.as_str()).ok()
-                    }
+/// Record what the transaction shows about the audited account
+fn consume_transaction(
+    endpoints: &Endpoints,
+    throttle: &Throttle,
+    multisig_configs: &mut HashMap<Pubkey, Option<MultisigConfig>>,
+    reported_token_address: &Pubkey,
+    token_account_entry: &mut TokenAccountEntry,
+    sig: Signature,
+    confirmation: EncodedConfirmedTransaction,
+) {
+    let slot = confirmation.slot;
+    let EncodedTransactionWithStatusMeta { transaction, meta } = confirmation.transaction;
+    let transaction = if let Some(transaction) = transaction.decode() {
+        transaction
+    } else {
+        eprintln!(
+            "undecodable transaction {} for {}",
+            sig, reported_token_address
+        );
+        token_account_entry
+            .unknown_instructions
+            .push(UnknownInstruction {
+                slot,
+                transaction_id: sig,
+                original_ix: "undecodable transaction".to_string(),
+            });
+        return;
+    };
+    let account_keys = transaction.message.account_keys;
+    let balance_change = meta.as_ref().and_then(|meta| {
+        let account_index = account_keys
+            .iter()
+            .position(|key| key == reported_token_address)?;
+        token_balance_change(meta, account_index)
+    });
+    let inner_instructions = meta
+        .and_then(|meta| meta.inner_instructions)
+        .unwrap_or_default();
+
+    let instructions = flatten_instructions(
+        transaction.message.instructions,
+        inner_instructions,
+        &account_keys,
+    );
+
+    // only spl token instructions will be parsed
+    let mut new_ix_in_tx = true;
+    for (instruction_index, (outer_instruction_index, outer_program_id, ix)) in
+        instructions.into_iter().enumerate()
+    {
+        let instruction = if let Some(instruction) = ix {
+            instruction
+        } else {
+            eprintln!(
+                "undecodable inner instruction in {} for {}",
+                sig, reported_token_address
+            );
+            token_account_entry
+                .unknown_instructions
+                .push(UnknownInstruction {
+                    slot,
+                    transaction_id: sig,
+                    original_ix: "undecodable inner instruction".to_string(),
+                });
+            continue;
+        };
+        let program_id = account_keys.get(instruction.program_id_index as usize);
+        if program_id != Some(&spl_token::id()) {
+            continue;
+        }
+
+        if new_ix_in_tx {
+            new_ix_in_tx = false;
+            token_account_entry.scanned_tx_count += 1;
+        }
+        token_account_entry.scanned_spl_token_ix_count += 1;
+        let ix = DecodedInstruction::decode(
+            instruction_index,
+            outer_instruction_index,
+            outer_program_id,
+            &instruction,
+            &account_keys,
+        );
+        // the multisig tells whether the current owner signed, which only matters for
+        // instructions on the audited account
+        let multisig_authority = ix
+            .multisig_authority()
+            .filter(|_| ix.accounts.contains(reported_token_address));
+        if let Some((multisig, _multisig_signers)) = multisig_authority {
+            if let Some(multisig_config) = multisig_configs
+                .entry(multisig)
+                .or_insert_with(|| get_multisig_config(endpoints, throttle, &multisig))
+            {
+                token_account_entry
+                    .multisigs
+                    .insert(multisig, multisig_config.clone());
+            }
+        }
+        let unrecognized = try_to_recognize_and_consume_ix(
+            *reported_token_address,
+            token_account_entry,
+            slot,
+            sig,
+            &ix,
+        );
+        if unrecognized {
+            eprintln!(
+                "unknown instruction in {} for {}: {}",
+                sig, reported_token_address, ix.original_ix
+            );
+            token_account_entry
+                .unknown_instructions
+                .push(UnknownInstruction {
+                    slot,
+                    transaction_id: sig,
+                    original_ix: ix.original_ix,
                 });
-                token_account_entry.failed_tx_count += total_sig_len - sigs.clone().count();
-
-                for sig in sigs {
-                    #[allow(deprecated)]
-                    let confirmation = rpc_client
-                        .get_confirmed_transaction(&sig, UiTransactionEncoding::JsonParsed)
-                        .unwrap();
-                    let slot = confirmation.slot;
-                    let EncodedTransactionWithStatusMeta { transaction, meta } =
-                        confirmation.transaction;
-                    let inner_ix = meta.and_then(|meta| {
-                        meta.inner_instructions
-                            .map(|ixs| ixs.into_iter().map(|ixs| ixs.instructions).flatten())
+        }
+    }
+    token_account_entry.set_balance_change(&sig, balance_change);
+}
+
+/// Resolve the order of the transactions which share a slot from their blocks
+fn order_transactions_in_slots(
+    endpoints: &Endpoints,
+    throttle: &Throttle,
+    token_account_entry: &mut TokenAccountEntry,
+) {
+    for slot in token_account_entry.slots_with_unordered_transactions() {
+        let block = match throttle.call(|| {
+            endpoints.try_each(Role::History, |rpc_client| {
+                rpc_client.get_block_with_config(
+                    slot,
+                    RpcBlockConfig {
+                        encoding: None,
+                        transaction_details: Some(TransactionDetails::Signatures),
+                        rewards: Some(false),
+                        commitment: Some(rpc_client.commitment()),
+                    },
+                )
+            })
+        }) {
+            Ok((block, url)) => {
+                token_account_entry
+                    .history_endpoints
+                    .insert(url.to_string());
+                block
+            }
+            Err(err) => {
+                let error = format!("couldn't fetch block {}: {}", slot, err);
+                eprintln!("{}", error);
+                token_account_entry.mark_incomplete(error);
+                continue;
+            }
+        };
+        let transaction_indexes = block
+            .signatures
+            .unwrap_or_default()
+            .iter()
+            .enumerate()
+            .filter_map(|(transaction_index, signature)| {
+                Some((Signature::from_str(signature).ok()?, transaction_index))
+            })
+            .collect::<HashMap<_, _>>();
+        token_account_entry.set_transaction_indexes(slot, &transaction_indexes);
+    }
+}
+
+/// A token account to audit, with what's known about it before fetching its
+/// history
+struct AuditTarget {
+    owner: Pubkey,
+    token_address: Pubkey,
+    account: spl_token::state::Account,
+    historical: bool,
+    closed: bool,
+    owner_metadata: Vec<String>,
+}
+
+pub fn run(config: Config, scope: AuditScope) {
+    println!("audit");
+    let mut report = Report::new();
+    if let AuditScope::Owners { owner_metadata, .. } = &scope {
+        report.owner_metadata_columns = owner_metadata.columns.clone();
+    }
+    let endpoints = &config.endpoints;
+    let cache = config.cache.as_ref();
+    let concurrency = config.concurrency;
+    let throttle = Throttle::new(concurrency);
+    let mut outbound_reassignments = Vec::<OutboundReassignment>::new();
+
+    // find every account to audit first, in a fixed order, so that the report doesn't depend on
+    // the order in which their histories are fetched
+    let mut targets = Vec::<AuditTarget>::new();
+    match &scope {
+        AuditScope::Owners {
+            owners,
+            mints,
+            historical,
+            outbound,
+            owner_metadata,
+        } => {
+            let failed_owners = crate::for_all_spl_token_accounts(
+                &config,
+                owners.as_slice(),
+                mints.as_deref(),
+                |_config, owner, reported_token_address, account| {
+                    targets.push(AuditTarget {
+                        owner: owner.pubkey(),
+                        token_address: *reported_token_address,
+                        account: *account,
+                        historical: false,
+                        closed: false,
+                        owner_metadata: owner_metadata.values(&owner.pubkey()),
                     });
-                    let mut instructions =
-                        if let EncodedTransaction::Json(transaction) = transaction {
-                            if let UiMessage::Parsed(message) = transaction.message {
-                                message.instructions
-                            } else {
-                                Vec::new()
-                            }
-                        } else {
-                            Vec::new()
-                        };
+                },
+            );
+            for (owner, err) in failed_owners {
+                report.incomplete_owners.push(IncompleteOwner {
+                    owner,
+                    error: format!("couldn't list the token accounts: {}", err),
+                    owner_metadata: owner_metadata.values(&owner),
+                });
+            }
 
-                    if let Some(inner_ix) = inner_ix {
-                        instructions.extend(inner_ix);
+            if *historical || *outbound {
+                let mut audited_token_addresses = targets
+                    .iter()
+                    .map(|target| target.token_address)
+                    .collect::<HashSet<_>>();
+                let mut already_processed = HashSet::new();
+                let owner_pubkeys = owners
+                    .iter()
+                    .map(|owner| owner.pubkey())
+                    .filter(|owner_pubkey| already_processed.insert(*owner_pubkey))
+                    .collect::<Vec<_>>();
+                let histories = fetch::parallel_map(concurrency, &owner_pubkeys, |owner_pubkey| {
+                    wallet_history(endpoints, cache, &throttle, concurrency, owner_pubkey)
+                });
+                for (owner_pubkey, history) in owner_pubkeys.iter().zip(histories) {
+                    let history = match history {
+                        Ok(history) => history,
+                        Err(error) => {
+                            eprintln!("{}", error);
+                            report.incomplete_owners.push(IncompleteOwner {
+                                owner: *owner_pubkey,
+                                error,
+                                owner_metadata: owner_metadata.values(owner_pubkey),
+                            });
+                            continue;
+                        }
+                    };
+                    // the latest owner each account was handed to
+                    let reassigned_to = history
+                        .outbound_reassignments
+                        .iter()
+                        .map(|reassignment| (reassignment.token_address, reassignment.new_owner))
+                        .collect::<HashMap<_, _>>();
+                    if *outbound {
+                        outbound_reassignments.extend(
+                            history
+                                .outbound_reassignments
+                                .into_iter()
+                                .map(|reassignment| OutboundReassignment {
+                                    owner_metadata: owner_metadata.values(owner_pubkey),
+                                    ..reassignment
+                                }),
+                        );
                     }
-
-                    // only spl token instructions will be parsed
-                    let mut new_ix_in_tx = true;
-                    instructions
+                    let candidates = history
+                        .token_accounts
                         .into_iter()
-                        .filter_map(|ix| {
-                            if let UiInstruction::Parsed(UiParsedInstruction::Parsed(instruction)) =
-                                ix
-                            {
-                                let program_id =
-                                    Pubkey::from_str(instruction.program_id.as_str()).unwrap();
-                                if program_id == spl_token::id() {
-                                    Some((program_id, instruction.parsed))
-                                } else {
-                                    None
+                        .filter(|(token_address, _mint)| {
+                            !audited_token_addresses.contains(token_address)
+                                && (*historical || reassigned_to.contains_key(token_address))
+                        })
+                        .collect::<Vec<_>>();
+                    let accounts =
+                        fetch::parallel_map(concurrency, &candidates, |(token_address, _mint)| {
+                            match throttle.call(|| {
+                                endpoints.try_each(Role::Accounts, |rpc_client| {
+                                    rpc_client.get_account(token_address)
+                                })
+                            }) {
+                                Ok((account, _url)) if account.owner == spl_token::id() => {
+                                    Ok(spl_token::state::Account::unpack(&account.data).ok())
                                 }
-                            } else {
-                                None
+                                Err(err) if fetch::is_transient(&err.kind) => Err(err),
+                                // gone, or reused for something else
+                                _ => Ok(None),
                             }
-                        })
-                        // program_id must be the tokenkeg according the previous .filter_map()
-                        .filter(|(_program_id, ix)| {
-                            if new_ix_in_tx {
-                                new_ix_in_tx = false;
-                                token_account_entry.scanned_tx_count += 1;
+                        });
+                    for ((token_address, mint), account) in candidates.into_iter().zip(accounts) {
+                        let account = match account {
+                            Ok(account) => account,
+                            Err(err) => {
+                                let error = format!(
+                                    "couldn't fetch former token account {}: {}",
+                                    token_address, err
+                                );
+                                eprintln!("{}", error);
+                                report.incomplete_owners.push(IncompleteOwner {
+                                    owner: *owner_pubkey,
+                                    error,
+                                    owner_metadata: owner_metadata.values(owner_pubkey),
+                                });
+                                continue;
                             }
-                            token_account_entry.scanned_spl_token_ix_count += 1;
-                            try_to_recognize_and_consume_ix(
-                                owner_pubkey,
-                                *reported_token_address,
-                                account.mint,
-                                &mut token_account_entry,
-                                slot,
-                                sig,
-                                ix,
-                            )
-                        })
-                        .for_each(|(program_id, ix)| {
-                            dbg!(("unknown instruction!", program_id, ix));
-                            panic!();
+                        };
+                        let closed = account.is_none();
+                        let account = match (account, mint) {
+                            (Some(account), _) => account,
+                            // closed since, so all that's left is its history
+                            (None, Some(mint)) => spl_token::state::Account {
+                                mint,
+                                owner: reassigned_to
+                                    .get(&token_address)
+                                    .copied()
+                                    .unwrap_or(*owner_pubkey),
+                                ..spl_token::state::Account::default()
+                            },
+                            (None, None) => {
+                                eprintln!(
+                                    "couldn't find the mint of closed account {} of {}, skipping",
+                                    token_address, owner_pubkey
+                                );
+                                continue;
+                            }
+                        };
+                        if let Some(mints) = &mints {
+                            if !mints.contains(&account.mint) {
+                                continue;
+                            }
+                        }
+                        audited_token_addresses.insert(token_address);
+                        targets.push(AuditTarget {
+                            owner: account.owner,
+                            token_address,
+                            account,
+                            historical: true,
+                            closed,
+                            owner_metadata: owner_metadata.values(owner_pubkey),
                         });
+                    }
+                }
+            }
+        }
+        AuditScope::MintWide(mint) => {
+            crate::for_all_spl_token_accounts_of_mint(
+                &config,
+                mint,
+                |_config, reported_token_address, account| {
+                    targets.push(AuditTarget {
+                        owner: account.owner,
+                        token_address: *reported_token_address,
+                        account: *account,
+                        historical: false,
+                        closed: false,
+                        owner_metadata: vec![],
+                    });
+                },
+            )
+            .unwrap_or_else(|err| {
+                eprintln!(
+                    "error: couldn't list the token accounts of mint {}: {}",
+                    mint, err
+                );
+                std::process::exit(1);
+            });
+        }
+        AuditScope::Accounts(token_addresses) => {
+            let mut already_processed = HashSet::new();
+            for token_address in token_addresses {
+                if !already_processed.insert(*token_address) {
+                    continue;
+                }
+                match get_token_account(endpoints, token_address) {
+                    Ok(account) => targets.push(AuditTarget {
+                        owner: account.owner,
+                        token_address: *token_address,
+                        account,
+                        historical: false,
+                        closed: false,
+                        owner_metadata: vec![],
+                    }),
+                    Err(error) => {
+                        eprintln!("{}, skipping", error);
+                        report.incomplete_accounts.push(IncompleteAccount {
+                            token_address: *token_address,
+                            error,
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    let mut multisig_configs = HashMap::<Pubkey, Option<MultisigConfig>>::new();
+    let mut mint_decimals = HashMap::<Pubkey, Option<u8>>::new();
+    // a few accounts per worker at a time, so that short histories are fetched in parallel too
+    for targets in targets.chunks(concurrency * TARGETS_PER_WORKER) {
+        let all_sigs = fetch::parallel_map(concurrency, targets, |target| {
+            fetch::get_all_signatures(endpoints, cache, &throttle, &target.token_address)
+        });
+
+        let mut pending = vec![];
+        for (target_index, (target, sigs)) in targets.iter().zip(all_sigs).enumerate() {
+            let token_account_entry = report
+                .entries_by_token_address
+                .entry(target.token_address)
+                .or_insert_with(|| TokenAccountEntry::new(target.owner, target.account.mint));
+            token_account_entry.historical = target.historical;
+            token_account_entry.closed = target.closed;
+            token_account_entry.associated = target.token_address
+                == get_associated_token_address(&target.owner, &target.account.mint);
+            token_account_entry.owner_metadata = target.owner_metadata.clone();
+            token_account_entry.current_amount = target.account.amount;
+            token_account_entry.current_delegate = target.account.delegate.into();
+            match mint_decimals
+                .entry(target.account.mint)
+                .or_insert_with(|| get_mint_decimals(endpoints, &target.account.mint))
+            {
+                Some(decimals) => token_account_entry.decimals = *decimals,
+                None => eprintln!(
+                    "couldn't fetch mint {} of {}, amounts of unchecked instructions will be raw",
+                    target.account.mint, target.token_address
+                ),
+            }
+
+            let sigs = match sigs {
+                Ok((sigs, urls)) => {
+                    token_account_entry.history_endpoints.extend(urls);
+                    sigs
+                }
+                Err(err) => {
+                    let error = format!("couldn't fetch the signatures: {}", err);
+                    eprintln!("{} of {}", error, target.token_address);
+                    token_account_entry.mark_incomplete(error);
+                    continue;
                 }
+            };
 
-                // last
-                if before.is_none() {
-                    break;
+            // Exclude any transactions which failed
+            let successful_sigs = fetch::successful_signatures(&sigs)
+                .map(|(sig, _slot)| sig)
+                .collect::<Vec<_>>();
+            token_account_entry.total_tx_count += sigs.len();
+            token_account_entry.failed_tx_count += sigs.len() - successful_sigs.len();
+            pending.extend(successful_sigs.into_iter().map(|sig| (target_index, sig)));
+        }
+
+        // a page at a time, so that long histories aren't all held in memory
+        for page in pending.chunks(TRANSACTIONS_PER_PAGE) {
+            let page_signatures = page.iter().map(|(_, sig)| *sig).collect::<Vec<_>>();
+            let confirmations =
+                fetch::get_transactions(endpoints, cache, &throttle, concurrency, &page_signatures);
+            for (&(target_index, sig), confirmation) in page.iter().zip(confirmations) {
+                let reported_token_address = &targets[target_index].token_address;
+                let token_account_entry = report
+                    .entries_by_token_address
+                    .get_mut(reported_token_address)
+                    .unwrap();
+                match confirmation {
+                    Ok((confirmation, url)) => {
+                        token_account_entry.history_endpoints.insert(url);
+                        consume_transaction(
+                            endpoints,
+                            &throttle,
+                            &mut multisig_configs,
+                            reported_token_address,
+                            token_account_entry,
+                            sig,
+                            confirmation,
+                        )
+                    }
+                    Err(err) => {
+                        let error = format!("couldn't fetch transaction {}: {}", sig, err);
+                        eprintln!("{} of {}", error, reported_token_address);
+                        token_account_entry.mark_incomplete(error);
+                    }
                 }
             }
-        },
-    )
-    .unwrap();
+        }
+
+        for target in targets {
+            if let Some(token_account_entry) = report
+                .entries_by_token_address
+                .get_mut(&target.token_address)
+            {
+                order_transactions_in_slots(endpoints, &throttle, token_account_entry);
+            }
+        }
+    }
+
+    // the ones left out by the mint filter weren't audited
+    report.outbound_reassignments = outbound_reassignments
+        .into_iter()
+        .filter(|reassignment| {
+            report
+                .entries_by_token_address
+                .contains_key(&reassignment.token_address)
+        })
+        .collect();
 
     report.summary(std::io::stdout()).unwrap();
     println!();
+    report.incomplete(std::io::stdout()).unwrap();
+    println!();
+    report.losses(std::io::stdout()).unwrap();
+    println!();
+    if let AuditScope::MintWide(_) = scope {
+        report.exposure(std::io::stdout()).unwrap();
+        println!();
+    }
+    if let AuditScope::Owners { outbound: true, .. } = scope {
+        report.outbound(std::io::stdout()).unwrap();
+        println!();
+    }
     report.detail(std::io::stdout()).unwrap();
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use {
+        solana_account_decoder::parse_token::token_amount_to_ui_amount,
+        solana_sdk::message::Message,
+    };
+
+    fn mock_endpoints(address: &Pubkey, account: solana_sdk::account::Account) -> Endpoints {
+        use {
+            solana_account_decoder::{UiAccount, UiAccountEncoding},
+            solana_client::{
+                rpc_client::RpcClient,
+                rpc_request::RpcRequest,
+                rpc_response::{Response, RpcResponseContext},
+            },
+        };
+        let mut mocks = HashMap::new();
+        mocks.insert(
+            RpcRequest::GetAccountInfo,
+            serde_json::to_value(Response {
+                context: RpcResponseContext { slot: 1 },
+                value: Some(UiAccount::encode(
+                    address,
+                    &account,
+                    UiAccountEncoding::Base64,
+                    None,
+                    None,
+                )),
+            })
+            .unwrap(),
+        );
+        Endpoints::single(
+            "mock".to_string(),
+            RpcClient::new_mock_with_mocks("mock".to_string(), mocks),
+        )
+    }
+
+    #[test]
+    fn test_get_token_account() {
+        let token_address = Pubkey::new_unique();
+        let token_account = spl_token::state::Account {
+            mint: Pubkey::new_unique(),
+            owner: Pubkey::new_unique(),
+            amount: 42,
+            state: spl_token::state::AccountState::Initialized,
+            ..spl_token::state::Account::default()
+        };
+        let mut data = vec![0; spl_token::state::Account::LEN];
+        spl_token::state::Account::pack(token_account, &mut data).unwrap();
+        let account = solana_sdk::account::Account {
+            lamports: 1,
+            data,
+            owner: spl_token::id(),
+            executable: false,
+            rent_epoch: 0,
+        };
+
+        let endpoints = mock_endpoints(&token_address, account.clone());
+        assert_eq!(
+            get_token_account(&endpoints, &token_address),
+            Ok(token_account)
+        );
+
+        // a wallet address given by mistake
+        let endpoints = mock_endpoints(
+            &token_address,
+            solana_sdk::account::Account {
+                owner: solana_sdk::system_program::id(),
+                ..account.clone()
+            },
+        );
+        assert!(get_token_account(&endpoints, &token_address).is_err());
+
+        // a mint address given by mistake
+        let endpoints = mock_endpoints(
+            &token_address,
+            solana_sdk::account::Account {
+                data: vec![0; Mint::LEN],
+                ..account
+            },
+        );
+        assert!(get_token_account(&endpoints, &token_address).is_err());
+    }
+
+    fn decode(instruction: solana_sdk::instruction::Instruction) -> DecodedInstruction {
+        let message = Message::new(&[instruction], None);
+        DecodedInstruction::decode(0, 0, None, &message.instructions[0], &message.account_keys)
+    }
+
+    #[test]
+    fn test_multisig_approve_checked() {
+        let current_owner = Pubkey::new_unique();
+        let token_address = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let delegate = Pubkey::new_unique();
+        let multisig = Pubkey::new_unique();
+        let ix = decode(
+            spl_token::instruction::approve_checked(
+                &spl_token::id(),
+                &token_address,
+                &mint,
+                &delegate,
+                &multisig,
+                &[&Pubkey::new_unique(), &Pubkey::new_unique()],
+                999,
+                6,
+            )
+            .unwrap(),
+        );
+        let mut entry = TokenAccountEntry::new(current_owner, mint);
+        let unrecognized = try_to_recognize_and_consume_ix(
+            token_address,
+            &mut entry,
+            10,
+            Signature::default(),
+            &ix,
+        );
+        assert!(!unrecognized);
+        assert_eq!(entry.delegate_changes.len(), 1);
+        assert_eq!(entry.delegate_changes[0].signer, multisig);
+        assert_eq!(entry.delegate_changes[0].new_delegate, delegate);
+        assert_eq!(entry.delegate_changes[0].amount, 999);
+        assert_eq!(entry.delegate_changes[0].multisig_signers.len(), 2);
+        assert!(entry.delegate_changes[0]
+            .original_ix
+            .contains("approveChecked"));
+        assert_eq!(entry.instruction_counts.get("approveChecked"), Some(&1));
+    }
+
+    #[test]
+    fn test_multisig_with_current_owner_is_not_foreign() {
+        let current_owner = Pubkey::new_unique();
+        let token_address = Pubkey::new_unique();
+        let multisig = Pubkey::new_unique();
+        let ix = decode(
+            spl_token::instruction::transfer(
+                &spl_token::id(),
+                &token_address,
+                &Pubkey::new_unique(),
+                &multisig,
+                &[&current_owner],
+                42,
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            ix.multisig_authority(),
+            Some((multisig, &[current_owner][..]))
+        );
+
+        // a foreign multisig
+        let mut entry = TokenAccountEntry::new(current_owner, Pubkey::new_unique());
+        assert!(!try_to_recognize_and_consume_ix(
+            token_address,
+            &mut entry,
+            10,
+            Signature::default(),
+            &ix,
+        ));
+        assert_eq!(entry.possible_delegate_transfers.len(), 1);
+
+        // a multisig including the current owner
+        let mut entry = TokenAccountEntry::new(current_owner, Pubkey::new_unique());
+        entry.multisigs.insert(
+            multisig,
+            MultisigConfig {
+                m: 1,
+                n: 2,
+                members: vec![Pubkey::new_unique(), current_owner],
+            },
+        );
+        assert!(!try_to_recognize_and_consume_ix(
+            token_address,
+            &mut entry,
+            10,
+            Signature::default(),
+            &ix,
+        ));
+        assert!(entry.possible_delegate_transfers.is_empty());
+    }
+
+    #[test]
+    fn test_cpi_transfer_records_outer_program() {
+        let current_owner = Pubkey::new_unique();
+        let token_address = Pubkey::new_unique();
+        let delegate = Pubkey::new_unique();
+        let dex = Pubkey::new_unique();
+        let transfer = spl_token::instruction::transfer(
+            &spl_token::id(),
+            &token_address,
+            &Pubkey::new_unique(),
+            &delegate,
+            &[],
+            42,
+        )
+        .unwrap();
+        // the dex invokes the transfer, with the same accounts
+        let message = Message::new(
+            &[
+                solana_sdk::instruction::Instruction::new_with_bytes(dex, &[], vec![]),
+                solana_sdk::instruction::Instruction::new_with_bytes(
+                    dex,
+                    &[],
+                    transfer.accounts.clone(),
+                ),
+            ],
+            None,
+        );
+        let inner_transfer = message.instructions[1].clone();
+        let inner_instructions = vec![UiInnerInstructions {
+            index: 1,
+            instructions: vec![UiInstruction::Compiled(
+                solana_transaction_status::UiCompiledInstruction {
+                    // the token program is appended to the keys below
+                    program_id_index: message.account_keys.len() as u8,
+                    accounts: inner_transfer.accounts,
+                    data: bs58::encode(&transfer.data).into_string(),
+                },
+            )],
+        }];
+        let mut account_keys = message.account_keys.clone();
+        account_keys.push(spl_token::id());
+
+        let flattened =
+            flatten_instructions(message.instructions, inner_instructions, &account_keys);
+        assert_eq!(flattened.len(), 3);
+        let (outer_instruction_index, outer_program_id, instruction) = flattened[2].clone();
+        assert_eq!(outer_instruction_index, 1);
+        assert_eq!(outer_program_id, Some(dex));
+
+        let ix = DecodedInstruction::decode(
+            2,
+            outer_instruction_index,
+            outer_program_id,
+            &instruction.unwrap(),
+            &account_keys,
+        );
+        let mut entry = TokenAccountEntry::new(current_owner, Pubkey::new_unique());
+        assert!(!try_to_recognize_and_consume_ix(
+            token_address,
+            &mut entry,
+            10,
+            Signature::default(),
+            &ix,
+        ));
+        let transfer = &entry.possible_delegate_transfers[0];
+        assert_eq!(transfer.signer, delegate);
+        assert_eq!(transfer.instruction_index, 2);
+        assert_eq!(transfer.outer_instruction_index, 1);
+        assert_eq!(transfer.outer_program_id, Some(dex));
+    }
+
+    #[test]
+    fn test_historical_token_account() {
+        let wallet = Pubkey::new_unique();
+        let token_address = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+
+        // reassigned to the wallet
+        let ix = decode(
+            spl_token::instruction::set_authority(
+                &spl_token::id(),
+                &token_address,
+                Some(&wallet),
+                AuthorityType::AccountOwner,
+                &Pubkey::new_unique(),
+                &[],
+            )
+            .unwrap(),
+        );
+        assert_eq!(ix.historical_token_account(&wallet), Some(token_address));
+
+        // created for the wallet
+        let ix = decode(
+            spl_token::instruction::initialize_account(
+                &spl_token::id(),
+                &token_address,
+                &mint,
+                &wallet,
+            )
+            .unwrap(),
+        );
+        assert_eq!(ix.historical_token_account(&wallet), Some(token_address));
+
+        // closed into the wallet by someone else
+        let ix = decode(
+            spl_token::instruction::close_account(
+                &spl_token::id(),
+                &token_address,
+                &wallet,
+                &Pubkey::new_unique(),
+                &[],
+            )
+            .unwrap(),
+        );
+        assert_eq!(ix.historical_token_account(&wallet), Some(token_address));
+
+        // incoming transfers don't show ownership
+        let ix = decode(
+            spl_token::instruction::transfer(
+                &spl_token::id(),
+                &Pubkey::new_unique(),
+                &token_address,
+                &Pubkey::new_unique(),
+                &[],
+                1,
+            )
+            .unwrap(),
+        );
+        assert_eq!(ix.historical_token_account(&wallet), None);
+    }
+
+    #[test]
+    fn test_outbound_reassignment() {
+        let wallet = Pubkey::new_unique();
+        let token_address = Pubkey::new_unique();
+        let new_owner = Pubkey::new_unique();
+        let set_owner = |new_owner: &Pubkey, owner: &Pubkey| {
+            decode(
+                spl_token::instruction::set_authority(
+                    &spl_token::id(),
+                    &token_address,
+                    Some(new_owner),
+                    AuthorityType::AccountOwner,
+                    owner,
+                    &[],
+                )
+                .unwrap(),
+            )
+        };
+
+        // handed away by the wallet
+        let ix = set_owner(&new_owner, &wallet);
+        assert_eq!(
+            ix.outbound_reassignment(&wallet),
+            Some((token_address, new_owner))
+        );
+        // handed to the wallet
+        let ix = set_owner(&wallet, &new_owner);
+        assert_eq!(ix.outbound_reassignment(&wallet), None);
+        // the close authority is not the owner
+        let ix = decode(
+            spl_token::instruction::set_authority(
+                &spl_token::id(),
+                &token_address,
+                Some(&new_owner),
+                AuthorityType::CloseAccount,
+                &wallet,
+                &[],
+            )
+            .unwrap(),
+        );
+        assert_eq!(ix.outbound_reassignment(&wallet), None);
+    }
+
+    #[test]
+    fn test_token_balance_change() {
+        let token_balance = |account_index, amount| UiTransactionTokenBalance {
+            account_index,
+            mint: Pubkey::new_unique().to_string(),
+            ui_token_amount: token_amount_to_ui_amount(amount, 6),
+        };
+        let mut meta = UiTransactionStatusMeta {
+            err: None,
+            status: Ok(()),
+            fee: 5000,
+            pre_balances: vec![],
+            post_balances: vec![],
+            inner_instructions: None,
+            log_messages: None,
+            pre_token_balances: Some(vec![token_balance(1, 500), token_balance(2, 7)]),
+            post_token_balances: Some(vec![token_balance(1, 200)]),
+            rewards: None,
+        };
+        assert_eq!(token_balance_change(&meta, 1), Some(-300));
+        // closed in the transaction
+        assert_eq!(token_balance_change(&meta, 2), Some(-7));
+        // not a token account
+        assert_eq!(token_balance_change(&meta, 0), None);
+        // no token balances recorded
+        meta.post_token_balances = None;
+        assert_eq!(token_balance_change(&meta, 1), None);
+    }
+
+    #[test]
+    fn test_undecodable_instruction() {
+        let current_owner = Pubkey::new_unique();
+        let token_address = Pubkey::new_unique();
+        let ix = decode(solana_sdk::instruction::Instruction::new_with_bytes(
+            spl_token::id(),
+            &[255, 1, 2, 3],
+            vec![],
+        ));
+        let mut entry = TokenAccountEntry::new(current_owner, Pubkey::new_unique());
+        let unrecognized = try_to_recognize_and_consume_ix(
+            token_address,
+            &mut entry,
+            10,
+            Signature::default(),
+            &ix,
+        );
+        assert!(unrecognized);
+        assert!(entry.instruction_counts.is_empty());
+    }
+}
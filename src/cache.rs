@@ -0,0 +1,265 @@
+use {
+    serde::{Deserialize, Serialize},
+    solana_client::rpc_response::RpcConfirmedTransactionStatusWithSignature,
+    solana_sdk::{pubkey::Pubkey, signature::Signature},
+    solana_transaction_status::EncodedConfirmedTransaction,
+    std::{
+        collections::BTreeSet,
+        fs,
+        path::{Path, PathBuf},
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, SystemTime},
+    },
+};
+
+const TRANSACTIONS_DIR: &str = "transactions";
+const SIGNATURES_DIR: &str = "signatures";
+
+// tells apart the temporary files of concurrent writes
+static TEMPORARY_FILES: AtomicUsize = AtomicUsize::new(0);
+
+/// A transaction as fetched, along with the endpoint which answered for it
+#[derive(Debug, Deserialize, Serialize)]
+pub struct CachedTransaction {
+    pub endpoint: String,
+    pub transaction: EncodedConfirmedTransaction,
+}
+
+/// The signatures of an address as of the last audit, newest first, along
+/// with the endpoints which answered for them
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct CachedSignatures {
+    pub endpoints: BTreeSet<String>,
+    pub signatures: Vec<RpcConfirmedTransactionStatusWithSignature>,
+}
+
+/// What a cache directory holds
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub transactions: usize,
+    pub transaction_bytes: u64,
+    pub addresses: usize,
+    pub signature_bytes: u64,
+}
+
+/// Fetched transactions and signature lists, kept on disk across audits
+///
+/// Transactions never change once finalized, which is all that's fetched, so
+/// they're stored under their signature for good. Signature lists grow, so only the signatures newer than
+/// the cached ones are fetched again.
+pub struct Cache {
+    dir: PathBuf,
+}
+
+impl Cache {
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self, String> {
+        let dir = dir.as_ref().to_path_buf();
+        for subdir in [TRANSACTIONS_DIR, SIGNATURES_DIR] {
+            fs::create_dir_all(dir.join(subdir)).map_err(|err| {
+                format!("couldn't create cache directory {}: {}", dir.display(), err)
+            })?;
+        }
+        Ok(Self { dir })
+    }
+
+    fn transaction_path(&self, signature: &Signature) -> PathBuf {
+        let signature = signature.to_string();
+        // sharded by the first characters, so that no directory gets too large
+        self.dir
+            .join(TRANSACTIONS_DIR)
+            .join(&signature[..2])
+            .join(format!("{}.json", signature))
+    }
+
+    fn signatures_path(&self, address: &Pubkey) -> PathBuf {
+        self.dir
+            .join(SIGNATURES_DIR)
+            .join(format!("{}.json", address))
+    }
+
+    pub fn get_transaction(&self, signature: &Signature) -> Option<CachedTransaction> {
+        read(&self.transaction_path(signature))
+    }
+
+    pub fn put_transaction(&self, signature: &Signature, cached: &CachedTransaction) {
+        write(&self.transaction_path(signature), cached);
+    }
+
+    pub fn get_signatures(&self, address: &Pubkey) -> Option<CachedSignatures> {
+        read(&self.signatures_path(address))
+    }
+
+    pub fn put_signatures(&self, address: &Pubkey, cached: &CachedSignatures) {
+        write(&self.signatures_path(address), cached);
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let mut stats = CacheStats::default();
+        for (path, metadata) in files(&self.dir.join(TRANSACTIONS_DIR)) {
+            if is_entry(&path) {
+                stats.transactions += 1;
+                stats.transaction_bytes += metadata.len();
+            }
+        }
+        for (path, metadata) in files(&self.dir.join(SIGNATURES_DIR)) {
+            if is_entry(&path) {
+                stats.addresses += 1;
+                stats.signature_bytes += metadata.len();
+            }
+        }
+        stats
+    }
+
+    /// Remove the entries written longer ago than `older_than`, or all of them
+    /// without it, returning how many transactions and signature lists were
+    /// removed
+    pub fn prune(&self, older_than: Option<Duration>) -> (usize, usize) {
+        let now = SystemTime::now();
+        let prune_dir = |dir: &Path| {
+            files(dir)
+                .into_iter()
+                .filter(|(_path, metadata)| match older_than {
+                    Some(older_than) => metadata
+                        .modified()
+                        .ok()
+                        .and_then(|modified| now.duration_since(modified).ok())
+                        .map(|age| age > older_than)
+                        .unwrap_or(false),
+                    None => true,
+                })
+                .filter(|(path, _metadata)| match fs::remove_file(path) {
+                    Ok(()) => is_entry(path),
+                    Err(err) => {
+                        eprintln!("couldn't remove {}: {}", path.display(), err);
+                        false
+                    }
+                })
+                .count()
+        };
+        (
+            prune_dir(&self.dir.join(TRANSACTIONS_DIR)),
+            prune_dir(&self.dir.join(SIGNATURES_DIR)),
+        )
+    }
+}
+
+// leftover temporary files aren't entries, but get pruned all the same
+fn is_entry(path: &Path) -> bool {
+    path.extension().map(|extension| extension == "json") == Some(true)
+}
+
+// every file under the directory, recursively
+fn files(dir: &Path) -> Vec<(PathBuf, fs::Metadata)> {
+    let mut files = vec![];
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return files,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        match entry.metadata() {
+            Ok(metadata) if metadata.is_dir() => files.extend(self::files(&path)),
+            Ok(metadata) => files.push((path, metadata)),
+            Err(_) => continue,
+        }
+    }
+    files
+}
+
+// a missing or unreadable entry is a cache miss
+fn read<T: for<'de> Deserialize<'de>>(path: &Path) -> Option<T> {
+    let contents = fs::read(path).ok()?;
+    serde_json::from_slice(&contents).ok()
+}
+
+// written to a temporary file first, so that readers never see half an entry
+fn write<T: Serialize>(path: &Path, value: &T) {
+    let result = (|| {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let temporary_path = path.with_extension(format!(
+            "{}-{}.tmp",
+            std::process::id(),
+            TEMPORARY_FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        fs::write(&temporary_path, serde_json::to_vec(value)?)?;
+        fs::rename(&temporary_path, path)
+    })();
+    if let Err(err) = result {
+        eprintln!("couldn't write cache entry {}: {}", path.display(), err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        solana_transaction_status::{EncodedTransaction, EncodedTransactionWithStatusMeta},
+    };
+
+    fn new_cache() -> (Cache, PathBuf) {
+        let dir =
+            std::env::temp_dir().join(format!("inc-20210825-cache-test-{}", Pubkey::new_unique()));
+        (Cache::open(&dir).unwrap(), dir)
+    }
+
+    #[test]
+    fn transactions() {
+        let (cache, dir) = new_cache();
+        let signature = Signature::new(&[7; 64]);
+        assert!(cache.get_transaction(&signature).is_none());
+        cache.put_transaction(
+            &signature,
+            &CachedTransaction {
+                endpoint: "https://archive.example.com/".to_string(),
+                transaction: EncodedConfirmedTransaction {
+                    slot: 42,
+                    transaction: EncodedTransactionWithStatusMeta {
+                        transaction: EncodedTransaction::LegacyBinary("1".to_string()),
+                        meta: None,
+                    },
+                    block_time: None,
+                },
+            },
+        );
+        let cached = cache.get_transaction(&signature).unwrap();
+        assert_eq!(cached.endpoint, "https://archive.example.com/");
+        assert_eq!(cached.transaction.slot, 42);
+        assert_eq!(cache.stats().transactions, 1);
+
+        assert_eq!(cache.prune(Some(Duration::from_secs(3600))), (0, 0));
+        assert_eq!(cache.prune(None), (1, 0));
+        assert!(cache.get_transaction(&signature).is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn signatures() {
+        let (cache, dir) = new_cache();
+        let address = Pubkey::new_unique();
+        assert!(cache.get_signatures(&address).is_none());
+        let cached = CachedSignatures {
+            endpoints: vec!["https://archive.example.com/".to_string()]
+                .into_iter()
+                .collect(),
+            signatures: vec![RpcConfirmedTransactionStatusWithSignature {
+                signature: Signature::new(&[1; 64]).to_string(),
+                slot: 3,
+                err: None,
+                memo: None,
+                block_time: None,
+                confirmation_status: None,
+            }],
+        };
+        cache.put_signatures(&address, &cached);
+        assert_eq!(
+            cache.get_signatures(&address).unwrap().signatures,
+            cached.signatures
+        );
+        let stats = cache.stats();
+        assert_eq!((stats.transactions, stats.addresses), (0, 1));
+        assert_eq!(cache.prune(None), (0, 1));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,51 +1,214 @@
 use {
-    crate::config::Config,
+    crate::{
+        config::Config,
+        endpoints::Role,
+        owners::{OwnerKind, OwnerMetadata},
+    },
     solana_sdk::{
-        message::Message, program_option::COption, pubkey::Pubkey, signature::Signer,
-        transaction::Transaction,
+        instruction::Instruction, message::Message, program_option::COption, pubkey::Pubkey,
+        signature::Signer, transaction::Transaction,
     },
     spl_token::{self, instruction::revoke, state::Account},
+    std::collections::{BTreeSet, HashMap},
 };
 
-fn cleanup(config: &Config, owner: &dyn Signer, address: &Pubkey, account: &Account) {
-    if let COption::Some(delegate) = account.delegate {
-        println!("revoking delegate {} for account {}", delegate, address);
-        let rpc_client = &config.rpc_client;
-        let revoke_ix = revoke(&spl_token::id(), address, &owner.pubkey(), &[]).unwrap();
-        let fee_payer = config.fee_payer.pubkey();
-        let message = Message::new(&[revoke_ix], Some(&fee_payer));
-        let (blockhash, fee_calculator) = rpc_client.get_recent_blockhash().unwrap();
-        let fee_payer_balance = rpc_client.get_balance(&fee_payer).unwrap();
-        let fee = fee_calculator.calculate_fee(&message);
-        if !config.dry_run {
-            if fee_payer_balance < fee {
-                eprintln!("fee payer ({}) insufficient funds!", fee_payer);
-                std::process::exit(1);
-            }
+fn send_revoke(
+    config: &Config,
+    revoke_ix: Instruction,
+    signers: &[&dyn Signer],
+    delegate: &Pubkey,
+    address: &Pubkey,
+) {
+    let fee_payer = config.fee_payer.pubkey();
+    let message = Message::new(&[revoke_ix], Some(&fee_payer));
+    let fetched = config.endpoints.call(Role::Accounts, |rpc_client| {
+        let (blockhash, fee_calculator) = rpc_client.get_recent_blockhash()?;
+        Ok((
+            blockhash,
+            fee_calculator,
+            rpc_client.get_balance(&fee_payer)?,
+        ))
+    });
+    let (blockhash, fee_calculator, fee_payer_balance) = match fetched {
+        Ok(fetched) => fetched,
+        Err(error) => {
+            eprintln!(
+                "Error revoking delegate {} for account {}: {}",
+                delegate, address, error
+            );
+            return;
+        }
+    };
+    let fee = fee_calculator.calculate_fee(&message);
+    if !config.dry_run {
+        if fee_payer_balance < fee {
+            eprintln!("fee payer ({}) insufficient funds!", fee_payer);
+            std::process::exit(1);
+        }
+
+        let mut signers = signers.to_vec();
+        signers.push(config.fee_payer.as_ref());
+        let mut transaction = Transaction::new_unsigned(message);
+        transaction.sign(&signers, blockhash);
 
-            let mut transaction = Transaction::new_unsigned(message);
-            transaction.sign(&[owner, config.fee_payer.as_ref()], blockhash);
+        // the same transaction to the next endpoint if one fails, it can't land twice
+        match config.endpoints.try_each(Role::Accounts, |rpc_client| {
+            rpc_client.send_and_confirm_transaction_with_spinner(&transaction)
+        }) {
+            Ok((txid, _url)) => println!("txid: {}", txid),
+            Err(error) => eprintln!(
+                "Error revoking delegate {} for account {}: {}",
+                delegate, address, error
+            ),
+        }
+    }
+}
+
+// the revoke for someone else to sign, or for the owning program to invoke
+fn print_plan(owner_kind: &OwnerKind, revoke_ix: &Instruction) {
+    let accounts = revoke_ix
+        .accounts
+        .iter()
+        .map(|meta| {
+            serde_json::json!({
+                "pubkey": meta.pubkey.to_string(),
+                "isSigner": meta.is_signer,
+                "isWritable": meta.is_writable,
+            })
+        })
+        .collect::<Vec<_>>();
+    let plan = serde_json::json!({
+        "programId": revoke_ix.program_id.to_string(),
+        "accounts": accounts,
+        "data": bs58::encode(&revoke_ix.data).into_string(),
+    });
+    let owner = revoke_ix.accounts[1].pubkey;
+    println!("unsigned plan, {}: {}", owner_kind.describe(&owner), plan);
+}
 
-            match rpc_client.send_and_confirm_transaction_with_spinner(&transaction) {
-                Ok(txid) => println!("txid: {}", txid),
-                Err(error) => eprintln!(
-                    "Error revoking delegate {} for account {}: {}",
-                    delegate, address, error
-                ),
+fn cleanup(
+    config: &Config,
+    owner: &dyn Signer,
+    owner_kind: &OwnerKind,
+    multisig_signers: &[Box<dyn Signer>],
+    address: &Pubkey,
+    account: &Account,
+    owner_metadata: &OwnerMetadata,
+) {
+    if let COption::Some(delegate) = account.delegate {
+        let owner_address = owner.pubkey();
+        println!(
+            "revoking delegate {} for account {}{}",
+            delegate,
+            address,
+            owner_metadata.describe(&owner_address)
+        );
+        match owner_kind {
+            OwnerKind::Wallet => {
+                let revoke_ix = revoke(&spl_token::id(), address, &owner_address, &[]).unwrap();
+                send_revoke(config, revoke_ix, &[owner], &delegate, address);
+            }
+            OwnerKind::Multisig(multisig) => {
+                let members = &multisig.signers[..multisig.n as usize];
+                let signers = multisig_signers
+                    .iter()
+                    .filter(|signer| members.contains(&signer.pubkey()))
+                    .map(|signer| signer.as_ref())
+                    .take(multisig.m as usize)
+                    .collect::<Vec<_>>();
+                // the signers at hand first, then whichever members are still needed
+                let mut signer_pubkeys = signers
+                    .iter()
+                    .map(|signer| signer.pubkey())
+                    .collect::<Vec<_>>();
+                for member in members {
+                    if signer_pubkeys.len() == multisig.m as usize {
+                        break;
+                    }
+                    if !signer_pubkeys.contains(member) {
+                        signer_pubkeys.push(*member);
+                    }
+                }
+                let revoke_ix = revoke(
+                    &spl_token::id(),
+                    address,
+                    &owner_address,
+                    &signer_pubkeys.iter().collect::<Vec<_>>(),
+                )
+                .unwrap();
+                if signers.len() == multisig.m as usize {
+                    send_revoke(config, revoke_ix, &signers, &delegate, address);
+                } else {
+                    print_plan(owner_kind, &revoke_ix);
+                }
+            }
+            OwnerKind::Program(_) => {
+                let revoke_ix = revoke(&spl_token::id(), address, &owner_address, &[]).unwrap();
+                print_plan(owner_kind, &revoke_ix);
             }
         }
     }
 }
 
-pub fn run(config: Config, owners: Vec<Box<dyn Signer>>, mints: Option<Vec<Pubkey>>) {
+pub fn run(
+    config: Config,
+    owners: Vec<Box<dyn Signer>>,
+    mints: Option<Vec<Pubkey>>,
+    owner_kinds: &HashMap<Pubkey, OwnerKind>,
+    owner_metadata: &OwnerMetadata,
+    multisig_signers: &[Box<dyn Signer>],
+) {
     println!("cleanup");
-    crate::for_all_spl_token_accounts(&config, owners.as_slice(), mints.as_deref(), cleanup)
-        .unwrap();
+    // guessing the kind of an owner would build revokes it can't sign
+    let mut unknown_owners = BTreeSet::new();
+    let failed_owners = crate::for_all_spl_token_accounts(
+        &config,
+        owners.as_slice(),
+        mints.as_deref(),
+        |config, owner, address, account| {
+            let owner_kind = match owner_kinds.get(&owner.pubkey()) {
+                Some(owner_kind) => owner_kind,
+                None => {
+                    unknown_owners.insert(owner.pubkey());
+                    return;
+                }
+            };
+            cleanup(
+                config,
+                owner,
+                owner_kind,
+                multisig_signers,
+                address,
+                account,
+                owner_metadata,
+            )
+        },
+    );
+    // their delegations are still in place, so the cleanup didn't succeed
+    for owner in &unknown_owners {
+        eprintln!(
+            "couldn't clean up {}{}, it's unknown what kind of owner it is",
+            owner,
+            owner_metadata.describe(owner)
+        );
+    }
+    for (owner, err) in &failed_owners {
+        eprintln!(
+            "couldn't clean up {}{}, its token accounts couldn't be listed: {}",
+            owner,
+            owner_metadata.describe(owner),
+            err
+        );
+    }
+    if !unknown_owners.is_empty() || !failed_owners.is_empty() {
+        std::process::exit(1);
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::endpoints::Endpoints;
     use solana_client::rpc_client::RpcClient;
     use solana_sdk::signer::null_signer::NullSigner;
     use spl_token::state::AccountState;
@@ -64,11 +227,13 @@ mod tests {
         let mint = Pubkey::from_str("4k3Dyjzvzp8eMZWUXbBCjEvwSkkk59S5iCNLY3QrkX6R").unwrap();
         let delegate = Pubkey::from_str("BaDmyYaaua9k8mZuL53UUt8E6peRMeT3cRVjYcLm68T7").unwrap();
         let config = Config {
-            json_rpc_url,
-            rpc_client,
+            endpoints: Endpoints::single(json_rpc_url, rpc_client),
             fee_payer,
             dry_run: true,
             verbose: true,
+            token_accounts_by_owner: false,
+            concurrency: 1,
+            cache: None,
         };
         let account = Account {
             mint,
@@ -80,6 +245,14 @@ mod tests {
             delegated_amount: 999,
             close_authority: COption::None,
         };
-        cleanup(&config, &wallet, &mint, &account);
+        cleanup(
+            &config,
+            &wallet,
+            &OwnerKind::Wallet,
+            &[],
+            &mint,
+            &account,
+            &OwnerMetadata::default(),
+        );
     }
 }
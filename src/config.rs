@@ -1,9 +1,18 @@
-use {solana_client::rpc_client::RpcClient, solana_sdk::signature::Signer};
+use {
+    crate::{cache::Cache, endpoints::Endpoints},
+    solana_sdk::signature::Signer,
+};
 
 pub struct Config {
-    pub rpc_client: RpcClient,
-    pub json_rpc_url: String,
+    pub endpoints: Endpoints,
     pub fee_payer: Box<dyn Signer>,
     pub dry_run: bool,
     pub verbose: bool,
+    // enumerate token accounts with getTokenAccountsByOwner instead of getProgramAccounts, which
+    // most hosted RPC providers disable or throttle
+    pub token_accounts_by_owner: bool,
+    // how many RPC requests the audit sends at once, at most
+    pub concurrency: usize,
+    // where fetched transactions and signature lists are kept across audits, if anywhere
+    pub cache: Option<Cache>,
 }
@@ -0,0 +1,178 @@
+use {
+    bip39::{Language, Mnemonic},
+    solana_sdk::{
+        derivation_path::DerivationPath,
+        signature::{
+            generate_seed_from_seed_phrase_and_passphrase, keypair_from_seed_and_derivation_path,
+            Keypair,
+        },
+    },
+    std::ops::Range,
+};
+
+// the derived paths always sit under the Solana BIP44 prefix
+const SOLANA_PREFIX: &str = "m/44'/501'/";
+
+/// Wallets derived from one seed, over a range of indexes substituted into a
+/// derivation path template
+///
+/// Templates are Solana key paths with `{}` standing for the index, such as
+/// `{}/0` or `0/{}`, optionally spelled out in full as `m/44'/501'/{}'/0'`.
+/// Every level is hardened, just like with the `prompt://?key=` keypair URIs.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DerivationRange {
+    template: String,
+    indexes: Range<u32>,
+}
+
+impl DerivationRange {
+    pub fn new(template: &str, indexes: &str) -> Result<Self, String> {
+        let template = template
+            .strip_prefix(SOLANA_PREFIX)
+            .unwrap_or(template)
+            .replace('\'', "");
+        if template.matches("{}").count() != 1 {
+            return Err(format!(
+                "derivation path template {} needs exactly one {{}} for the index",
+                template
+            ));
+        }
+        let indexes = parse_index_range(indexes)?;
+        let range = Self { template, indexes };
+        // catch a bad template before asking for the seed phrase
+        range.derivation_path(range.indexes.start)?;
+        Ok(range)
+    }
+
+    /// The key path of the index, like `3/0`
+    pub fn key_path(&self, index: u32) -> String {
+        self.template.replace("{}", &index.to_string())
+    }
+
+    pub fn derivation_path(&self, index: u32) -> Result<DerivationPath, String> {
+        let key_path = self.key_path(index);
+        DerivationPath::from_key_str(&key_path)
+            .map_err(|err| format!("invalid derivation path {}: {}", key_path, err))
+    }
+
+    /// Keypairs of every index in the range, along with their full derivation
+    /// paths, like `m/44'/501'/3'/0'`
+    pub fn derive(&self, seed: &[u8]) -> Result<Vec<(String, Keypair)>, String> {
+        self.indexes
+            .clone()
+            .map(|index| {
+                let derivation_path = self.derivation_path(index)?;
+                let keypair = keypair_from_seed_and_derivation_path(seed, Some(derivation_path))
+                    .map_err(|err| format!("couldn't derive index {}: {}", index, err))?;
+                let hardened = self
+                    .key_path(index)
+                    .split('/')
+                    .map(|level| format!("{}'", level))
+                    .collect::<Vec<_>>()
+                    .join("/");
+                Ok((format!("{}{}", SOLANA_PREFIX, hardened), keypair))
+            })
+            .collect()
+    }
+}
+
+// `START..END`, excluding END
+fn parse_index_range(indexes: &str) -> Result<Range<u32>, String> {
+    let invalid = || format!("invalid index range {}, expected START..END", indexes);
+    let (start, end) = indexes.split_once("..").ok_or_else(invalid)?;
+    let start = start.trim().parse::<u32>().map_err(|_| invalid())?;
+    let end = end.trim().parse::<u32>().map_err(|_| invalid())?;
+    if start >= end {
+        return Err(format!("index range {} is empty", indexes));
+    }
+    Ok(start..end)
+}
+
+/// The seed from a BIP39 seed phrase file, or prompted for without one
+///
+/// Seed phrase files hold the phrase on the first line, and optionally the
+/// passphrase on the second one.
+pub fn read_seed(seed_phrase_file: Option<&str>) -> Result<Vec<u8>, String> {
+    let (seed_phrase, passphrase) = if let Some(path) = seed_phrase_file {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("couldn't read seed phrase file {}: {}", path, err))?;
+        let mut lines = contents.lines();
+        let seed_phrase = lines.next().unwrap_or_default().to_string();
+        let passphrase = lines.next().unwrap_or_default().to_string();
+        (seed_phrase, passphrase)
+    } else {
+        let seed_phrase = rpassword::prompt_password_stderr("Seed phrase: ")
+            .map_err(|err| format!("couldn't read seed phrase: {}", err))?;
+        let passphrase = rpassword::prompt_password_stderr(
+            "If this seed phrase has an associated passphrase, enter it now. Otherwise, press ENTER to continue: ",
+        )
+        .map_err(|err| format!("couldn't read passphrase: {}", err))?;
+        (seed_phrase, passphrase)
+    };
+    seed_from_phrase(&seed_phrase, &passphrase)
+}
+
+fn seed_from_phrase(seed_phrase: &str, passphrase: &str) -> Result<Vec<u8>, String> {
+    let seed_phrase = seed_phrase.split_whitespace().collect::<Vec<_>>().join(" ");
+    let mnemonic = Mnemonic::from_phrase(&seed_phrase, Language::English)
+        .map_err(|err| format!("invalid seed phrase: {}", err))?;
+    Ok(generate_seed_from_seed_phrase_and_passphrase(
+        mnemonic.phrase(),
+        passphrase,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, solana_sdk::signature::Signer};
+
+    const PHRASE: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    #[test]
+    fn index_range() {
+        assert_eq!(parse_index_range("0..3").unwrap(), 0..3);
+        assert_eq!(parse_index_range("10 .. 12").unwrap(), 10..12);
+        assert!(parse_index_range("3..3").is_err());
+        assert!(parse_index_range("3").is_err());
+        assert!(parse_index_range("a..b").is_err());
+    }
+
+    #[test]
+    fn derivation_path_template() {
+        let range = DerivationRange::new("m/44'/501'/{}'/0'", "0..2").unwrap();
+        assert_eq!(range, DerivationRange::new("{}/0", "0..2").unwrap());
+        assert_eq!(range.key_path(7), "7/0");
+        assert_eq!(
+            range.derivation_path(7).unwrap(),
+            DerivationPath::new_bip44(Some(7), Some(0))
+        );
+        assert!(DerivationRange::new("0/0", "0..2").is_err());
+        assert!(DerivationRange::new("{}/{}", "0..2").is_err());
+        assert!(DerivationRange::new("{}/0/0", "0..2").is_err());
+    }
+
+    #[test]
+    fn derive_wallets() {
+        let seed = seed_from_phrase(&format!("  {}\n", PHRASE), "").unwrap();
+        let wallets = DerivationRange::new("0/{}", "1..3")
+            .unwrap()
+            .derive(&seed)
+            .unwrap();
+        assert_eq!(wallets.len(), 2);
+        for (index, (path, keypair)) in (1..3).zip(wallets.iter()) {
+            assert_eq!(path, &format!("m/44'/501'/0'/{}'", index));
+            let expected = keypair_from_seed_and_derivation_path(
+                &seed,
+                Some(DerivationPath::new_bip44(Some(0), Some(index))),
+            )
+            .unwrap();
+            assert_eq!(keypair.pubkey(), expected.pubkey());
+        }
+        assert_ne!(
+            seed,
+            seed_from_phrase(PHRASE, "passphrase").unwrap(),
+            "the passphrase is part of the seed"
+        );
+        assert!(seed_from_phrase("abandon abandon", "").is_err());
+    }
+}
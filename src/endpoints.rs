@@ -0,0 +1,397 @@
+use {
+    crate::fetch,
+    reqwest::{header::CONTENT_TYPE, Url},
+    serde_json::{json, Value},
+    solana_clap_utils::input_validators::{is_url_or_moniker, normalize_to_url_if_moniker},
+    solana_client::{
+        client_error::Result as ClientResult,
+        rpc_client::RpcClient,
+        rpc_request::{RpcError, RpcRequest, RpcResponseErrorData},
+    },
+    solana_sdk::commitment_config::CommitmentConfig,
+    std::{sync::OnceLock, time::Duration},
+};
+
+// as long as the RPC client waits for an answer
+const BATCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The queries an RPC endpoint is used for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    /// Current state: accounts, mints, balances, blockhashes and transactions
+    /// to send, which any node can answer, so a fast one is best
+    Accounts,
+    /// Transaction history: signatures, transactions and blocks, which need
+    /// an archival node
+    History,
+    /// Both
+    All,
+}
+
+impl Role {
+    fn parse(role: &str) -> Option<Self> {
+        match role {
+            "accounts" => Some(Self::Accounts),
+            "history" => Some(Self::History),
+            "all" => Some(Self::All),
+            _ => None,
+        }
+    }
+
+    pub fn serves(&self, role: Role) -> bool {
+        *self == Role::All || *self == role
+    }
+}
+
+pub struct Endpoint {
+    pub url: String,
+    // only the scheme and host of the URL, for the report, see `redact`
+    pub name: String,
+    pub role: Role,
+    pub rpc_client: RpcClient,
+    // for batch requests, which the RPC client can't send, only built once needed
+    http_client: OnceLock<reqwest::blocking::Client>,
+}
+
+// subdomain labels this long are taken for API keys rather than names
+const MIN_KEY_LABEL_LEN: usize = 16;
+
+/// The URL reduced to its scheme, host and port, since an API key may be held
+/// in its path, query string or a subdomain
+fn redact(url: &str) -> String {
+    let parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        // not a URL, so keep no more than what comes before a path or query
+        Err(_) => {
+            return url
+                .split(&['/', '?'][..])
+                .next()
+                .unwrap_or_default()
+                .to_string()
+        }
+    };
+    let host = parsed.host_str().unwrap_or_default();
+    let labels = host.split('.').collect::<Vec<_>>();
+    // the domain itself is registered, so only the labels before it are masked
+    let subdomains = labels.len().saturating_sub(2);
+    let host = labels
+        .iter()
+        .enumerate()
+        .map(|(i, label)| {
+            if i < subdomains && label.len() >= MIN_KEY_LABEL_LEN {
+                "***"
+            } else {
+                label
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(".");
+    match parsed.port() {
+        Some(port) => format!("{}://{}:{}", parsed.scheme(), host, port),
+        None => format!("{}://{}", parsed.scheme(), host),
+    }
+}
+
+impl Endpoint {
+    pub fn new(url: String, role: Role) -> Self {
+        let rpc_client = RpcClient::new_with_commitment(url.clone(), CommitmentConfig::confirmed());
+        Self::with_rpc_client(url, role, rpc_client)
+    }
+
+    pub fn with_rpc_client(url: String, role: Role, rpc_client: RpcClient) -> Self {
+        let name = redact(&url);
+        Self {
+            url,
+            name,
+            role,
+            rpc_client,
+            http_client: OnceLock::new(),
+        }
+    }
+
+    /// An endpoint from `[ROLE=]URL_OR_MONIKER`, serving every query without a
+    /// role
+    pub fn parse(endpoint: &str) -> Result<Self, String> {
+        // the URL itself may hold a `=` in its query string
+        let (role, url) = match endpoint.split_once('=') {
+            Some((role, url)) if Role::parse(role).is_some() => (Role::parse(role).unwrap(), url),
+            _ => (Role::All, endpoint),
+        };
+        is_url_or_moniker(url)?;
+        Ok(Self::new(normalize_to_url_if_moniker(url), role))
+    }
+
+    /// Send the request once for each of the params, all in a single JSON-RPC
+    /// batch, returning the answer to each in turn
+    pub fn send_batch(
+        &self,
+        request: RpcRequest,
+        params: &[Value],
+    ) -> ClientResult<Vec<ClientResult<Value>>> {
+        let http_client = self.http_client.get_or_init(|| {
+            reqwest::blocking::Client::builder()
+                .timeout(BATCH_TIMEOUT)
+                .build()
+                .expect("build batch client")
+        });
+        let batch = params
+            .iter()
+            .enumerate()
+            .map(|(id, params)| {
+                json!({
+                    "jsonrpc": "2.0",
+                    "id": id,
+                    "method": request.to_string(),
+                    "params": params,
+                })
+            })
+            .collect::<Vec<_>>();
+        let response = http_client
+            .post(&self.url)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Value::Array(batch).to_string())
+            .send()?
+            .error_for_status()?;
+        batch_answers(params.len(), serde_json::from_str(&response.text()?)?)
+    }
+}
+
+// the answers may come in any order, so they're matched to the requests by id
+fn batch_answers(len: usize, response: Value) -> ClientResult<Vec<ClientResult<Value>>> {
+    let answers = match response {
+        Value::Array(answers) => answers,
+        // a single error instead, from endpoints which don't take batches
+        response => {
+            return Err(RpcError::RpcRequestError(format!("batch rejected: {}", response)).into())
+        }
+    };
+    let mut results = (0..len)
+        .map(|_| Err(RpcError::ForUser("no answer in the batch".to_string()).into()))
+        .collect::<Vec<ClientResult<Value>>>();
+    for mut answer in answers {
+        let id = match answer["id"].as_u64() {
+            Some(id) if (id as usize) < len => id as usize,
+            _ => continue,
+        };
+        results[id] = if answer["error"].is_object() {
+            Err(RpcError::RpcResponseError {
+                code: answer["error"]["code"].as_i64().unwrap_or_default(),
+                message: answer["error"]["message"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_string(),
+                data: RpcResponseErrorData::Empty,
+            }
+            .into())
+        } else {
+            Ok(answer["result"].take())
+        };
+    }
+    Ok(results)
+}
+
+// validator of `[ROLE=]URL_OR_MONIKER`, possibly comma separated
+pub fn is_endpoint_list(endpoints: String) -> Result<(), String> {
+    endpoints.split(',').try_for_each(|endpoint| {
+        let (role, url) = match endpoint.split_once('=') {
+            Some((role, url)) if Role::parse(role).is_some() => (role, url),
+            _ => ("all", endpoint),
+        };
+        is_url_or_moniker(url).map_err(|err| format!("invalid {} endpoint: {}", role, err))
+    })
+}
+
+/// The RPC endpoints in the order they're tried, each query failing over to
+/// the next endpoint serving its role on errors
+pub struct Endpoints {
+    pub endpoints: Vec<Endpoint>,
+}
+
+impl Endpoints {
+    pub fn new(endpoints: Vec<Endpoint>) -> Result<Self, String> {
+        let endpoints = Self { endpoints };
+        for (role, queries) in [(Role::Accounts, "account"), (Role::History, "history")] {
+            if endpoints.serving(role).next().is_none() {
+                return Err(format!("no RPC endpoint for {} queries", queries));
+            }
+        }
+        Ok(endpoints)
+    }
+
+    /// A single endpoint serving every query
+    pub fn single(url: String, rpc_client: RpcClient) -> Self {
+        Self {
+            endpoints: vec![Endpoint::with_rpc_client(url, Role::All, rpc_client)],
+        }
+    }
+
+    /// Endpoints from a list of `[ROLE=]URL_OR_MONIKER`, each possibly comma
+    /// separated
+    pub fn parse<'a>(endpoints: impl IntoIterator<Item = &'a str>) -> Result<Self, String> {
+        Self::new(
+            endpoints
+                .into_iter()
+                .flat_map(|endpoints| endpoints.split(','))
+                .map(Endpoint::parse)
+                .collect::<Result<_, _>>()?,
+        )
+    }
+
+    pub fn serving(&self, role: Role) -> impl Iterator<Item = &Endpoint> {
+        self.endpoints
+            .iter()
+            .filter(move |endpoint| endpoint.role.serves(role))
+    }
+
+    /// Send a request to each endpoint serving the role in turn, until one
+    /// answers, returning its name along with the answer, or the error of the
+    /// last one
+    pub fn try_each<T, F>(&self, role: Role, request: F) -> ClientResult<(T, &str)>
+    where
+        F: Fn(&RpcClient) -> ClientResult<T>,
+    {
+        self.try_each_endpoint(role, |endpoint| request(&endpoint.rpc_client))
+    }
+
+    /// Like `try_each`, for requests the RPC client can't send, such as batches
+    pub fn try_each_endpoint<T, F>(&self, role: Role, request: F) -> ClientResult<(T, &str)>
+    where
+        F: Fn(&Endpoint) -> ClientResult<T>,
+    {
+        let mut last_err = None;
+        for endpoint in self.serving(role) {
+            match request(endpoint) {
+                Ok(answer) => return Ok((answer, &endpoint.name)),
+                Err(err) => last_err = Some(err),
+            }
+        }
+        Err(last_err.unwrap())
+    }
+
+    /// Like `try_each`, retrying every endpoint while the errors are transient
+    pub fn call<T, F>(&self, role: Role, request: F) -> ClientResult<T>
+    where
+        F: Fn(&RpcClient) -> ClientResult<T>,
+    {
+        fetch::retry(|| self.try_each(role, &request)).map(|(answer, _url)| answer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        solana_client::{client_error::ClientErrorKind, rpc_request::RpcError},
+    };
+
+    #[test]
+    fn parse_endpoints() {
+        let endpoints = Endpoints::parse(vec![
+            "accounts=m,history=https://archive.example.com/?api-key=abc",
+            "https://other.example.com",
+        ])
+        .unwrap();
+        let roles = endpoints
+            .endpoints
+            .iter()
+            .map(|endpoint| (endpoint.name.as_str(), endpoint.role))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            roles,
+            vec![
+                ("https://api.mainnet-beta.solana.com", Role::Accounts),
+                ("https://archive.example.com", Role::History),
+                ("https://other.example.com", Role::All),
+            ]
+        );
+        assert_eq!(
+            endpoints
+                .serving(Role::History)
+                .map(|endpoint| endpoint.url.as_str())
+                .collect::<Vec<_>>(),
+            vec![
+                "https://archive.example.com/?api-key=abc",
+                "https://other.example.com"
+            ]
+        );
+        assert!(Endpoints::parse(vec!["accounts=m"]).is_err());
+        assert!(Endpoints::parse(vec!["archive=m"]).is_err());
+        assert!(is_endpoint_list("history=d,accounts=l".to_string()).is_ok());
+        assert!(is_endpoint_list("history=nowhere".to_string()).is_err());
+    }
+
+    #[test]
+    fn redact_api_keys() {
+        assert_eq!(
+            redact("https://rpc.example.com/v2/3d9b6e1f0c2a4b7e8f5d1c9a"),
+            "https://rpc.example.com"
+        );
+        assert_eq!(
+            redact("https://rpc.example.com/?api-key=abc"),
+            "https://rpc.example.com"
+        );
+        assert_eq!(
+            redact("https://3d9b6e1f0c2a4b7e8f5d1c9a.rpc.example.com/"),
+            "https://***.rpc.example.com"
+        );
+        assert_eq!(
+            redact("https://api.mainnet-beta.solana.com"),
+            "https://api.mainnet-beta.solana.com"
+        );
+        assert_eq!(redact("http://localhost:8899"), "http://localhost:8899");
+        assert_eq!(redact("mock"), "mock");
+    }
+
+    #[test]
+    fn fail_over() {
+        let endpoints = Endpoints::parse(vec!["accounts=l,history=d,t"]).unwrap();
+        let devnet = &endpoints.endpoints[1].rpc_client;
+        let attempts = std::cell::Cell::new(0);
+        let answer = endpoints.try_each(Role::History, |rpc_client| {
+            attempts.set(attempts.get() + 1);
+            if std::ptr::eq(rpc_client, devnet) {
+                Err(ClientErrorKind::RpcError(RpcError::ForUser("no history".to_string())).into())
+            } else {
+                Ok(42)
+            }
+        });
+        assert_eq!(answer.unwrap(), (42, "https://api.testnet.solana.com"));
+        // the localhost endpoint only serves account queries
+        assert_eq!(attempts.get(), 2);
+
+        let result = endpoints.try_each(Role::Accounts, |_rpc_client| -> ClientResult<()> {
+            Err(ClientErrorKind::Custom("down".to_string()).into())
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn match_batch_answers() {
+        let answers = batch_answers(
+            3,
+            json!([
+                {"jsonrpc": "2.0", "id": 2, "result": null},
+                {"jsonrpc": "2.0", "id": 0, "result": {"slot": 42}},
+                {"jsonrpc": "2.0", "id": 1, "error": {"code": -32004, "message": "Block not available"}},
+            ]),
+        )
+        .unwrap();
+        assert_eq!(answers[0].as_ref().unwrap(), &json!({"slot": 42}));
+        assert!(matches!(
+            &answers[1].as_ref().unwrap_err().kind,
+            ClientErrorKind::RpcError(RpcError::RpcResponseError { code: -32004, .. })
+        ));
+        assert_eq!(answers[2].as_ref().unwrap(), &Value::Null);
+
+        // an answer missing from the batch
+        let answers = batch_answers(2, json!([{"jsonrpc": "2.0", "id": 1, "result": 7}])).unwrap();
+        assert!(answers[0].is_err());
+        assert_eq!(answers[1].as_ref().unwrap(), &json!(7));
+
+        assert!(batch_answers(
+            2,
+            json!({"jsonrpc": "2.0", "id": null, "error": {"code": -32600, "message": "Invalid request"}}),
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,601 @@
+use {
+    crate::{
+        cache::{Cache, CachedSignatures, CachedTransaction},
+        endpoints::{Endpoints, Role},
+    },
+    rand::Rng,
+    serde_json::json,
+    solana_client::{
+        client_error::{ClientErrorKind, Result as ClientResult},
+        rpc_client::GetConfirmedSignaturesForAddress2Config,
+        rpc_custom_error::{
+            JSON_RPC_SERVER_ERROR_BLOCK_NOT_AVAILABLE,
+            JSON_RPC_SERVER_ERROR_KEY_EXCLUDED_FROM_SECONDARY_INDEX,
+            JSON_RPC_SERVER_ERROR_NODE_UNHEALTHY,
+        },
+        rpc_request::{RpcError, RpcRequest},
+        rpc_response::RpcConfirmedTransactionStatusWithSignature,
+    },
+    solana_sdk::{
+        account::Account, commitment_config::CommitmentConfig, pubkey::Pubkey, signature::Signature,
+    },
+    solana_transaction_status::{EncodedConfirmedTransaction, UiTransactionEncoding},
+    std::{
+        collections::BTreeSet,
+        str::FromStr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Condvar, Mutex,
+        },
+        time::Duration,
+    },
+};
+
+pub const DEFAULT_CONCURRENCY: usize = 8;
+const SIGNATURES_LIMIT: usize = 1000;
+// the most accounts an RPC node answers for in one getMultipleAccounts request
+const MULTIPLE_ACCOUNTS_LIMIT: usize = 100;
+// transactions per batch request, well within what hosted RPC providers accept
+const TRANSACTIONS_PER_BATCH: usize = 100;
+// how many times a request is retried after a transient error, on top of the retries of the RPC
+// client
+const RETRIES: u32 = 8;
+// pause before the first retry, doubled on every retry up to the longest pause
+const RETRY_PAUSE: Duration = Duration::from_millis(500);
+const LONGEST_RETRY_PAUSE: Duration = Duration::from_secs(30);
+// the JSON-RPC error code of methods the endpoint doesn't serve
+const JSON_RPC_METHOD_NOT_FOUND: i64 = -32601;
+
+fn is_rate_limited(kind: &ClientErrorKind) -> bool {
+    match kind {
+        ClientErrorKind::Reqwest(err) => err.status().map(|status| status.as_u16()) == Some(429),
+        _ => false,
+    }
+}
+
+/// Whether the request may well succeed if sent again: timeouts, dropped
+/// connections, rate limits, server errors and unhealthy nodes
+pub fn is_transient(kind: &ClientErrorKind) -> bool {
+    match kind {
+        ClientErrorKind::Io(_) => true,
+        ClientErrorKind::Reqwest(err) => match err.status() {
+            Some(status) => status.as_u16() == 429 || status.is_server_error(),
+            None => err.is_timeout() || err.is_connect() || err.is_request() || err.is_body(),
+        },
+        ClientErrorKind::RpcError(RpcError::RpcResponseError { code, .. }) => matches!(
+            *code,
+            JSON_RPC_SERVER_ERROR_BLOCK_NOT_AVAILABLE | JSON_RPC_SERVER_ERROR_NODE_UNHEALTHY
+        ),
+        _ => false,
+    }
+}
+
+/// Whether the endpoint refuses the method itself, rather than failing to
+/// answer it this once: methods it doesn't serve or forbids, and keys left out
+/// of its account index
+pub fn is_rejected(kind: &ClientErrorKind) -> bool {
+    match kind {
+        ClientErrorKind::Reqwest(err) => {
+            matches!(err.status().map(|status| status.as_u16()), Some(403 | 410))
+        }
+        ClientErrorKind::RpcError(RpcError::RpcResponseError { code, .. }) => matches!(
+            *code,
+            JSON_RPC_METHOD_NOT_FOUND | JSON_RPC_SERVER_ERROR_KEY_EXCLUDED_FROM_SECONDARY_INDEX
+        ),
+        _ => false,
+    }
+}
+
+// exponential backoff, with a random half of it so that the workers don't all retry at once
+fn retry_pause(retry: u32) -> Duration {
+    let pause = RETRY_PAUSE
+        .saturating_mul(2u32.saturating_pow(retry))
+        .min(LONGEST_RETRY_PAUSE);
+    pause / 2 + pause.mul_f64(rand::thread_rng().gen_range(0.0, 0.5))
+}
+
+/// Send a request, retrying it with exponential backoff and jitter as long as
+/// it fails with a transient error
+pub fn retry<T, F>(request: F) -> ClientResult<T>
+where
+    F: Fn() -> ClientResult<T>,
+{
+    let mut retry = 0;
+    loop {
+        match request() {
+            Err(err) if is_transient(&err.kind) && retry < RETRIES => {
+                std::thread::sleep(retry_pause(retry));
+                retry += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+struct ThrottleState {
+    limit: usize,
+    active: usize,
+    // successful requests since the limit was last changed
+    successes: usize,
+}
+
+/// Bounds how many RPC requests are in flight at once
+///
+/// The limit starts at the configured concurrency, and is halved every time
+/// the endpoint answers with 429 Too Many Requests. It then creeps back up by
+/// one after as many successful requests in a row as the current limit.
+pub struct Throttle {
+    max: usize,
+    state: Mutex<ThrottleState>,
+    released: Condvar,
+}
+
+impl Throttle {
+    pub fn new(max: usize) -> Self {
+        let max = max.max(1);
+        Self {
+            max,
+            state: Mutex::new(ThrottleState {
+                limit: max,
+                active: 0,
+                successes: 0,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.state.lock().unwrap().limit
+    }
+
+    fn acquire(&self) {
+        let mut state = self.state.lock().unwrap();
+        while state.active >= state.limit {
+            state = self.released.wait(state).unwrap();
+        }
+        state.active += 1;
+    }
+
+    fn release(&self, rate_limited: bool) {
+        let mut state = self.state.lock().unwrap();
+        state.active -= 1;
+        if rate_limited {
+            if state.limit > 1 {
+                state.limit /= 2;
+                eprintln!(
+                    "rate limited by the RPC endpoint, lowering concurrency to {}",
+                    state.limit
+                );
+            }
+            state.successes = 0;
+        } else {
+            state.successes += 1;
+            if state.successes >= state.limit && state.limit < self.max {
+                state.limit += 1;
+                state.successes = 0;
+            }
+        }
+        self.released.notify_all();
+    }
+
+    /// Send a request within the limit, retrying it like `retry` does
+    pub fn call<T, F>(&self, request: F) -> ClientResult<T>
+    where
+        F: Fn() -> ClientResult<T>,
+    {
+        retry(|| {
+            self.acquire();
+            let result = request();
+            self.release(matches!(&result, Err(err) if is_rate_limited(&err.kind)));
+            result
+        })
+    }
+}
+
+/// Map every item on up to `concurrency` threads, returning the results in
+/// the order of the items
+pub fn parallel_map<T, R, F>(concurrency: usize, items: &[T], f: F) -> Vec<R>
+where
+    T: Sync,
+    R: Send,
+    F: Fn(&T) -> R + Sync,
+{
+    if concurrency <= 1 || items.len() <= 1 {
+        return items.iter().map(f).collect();
+    }
+    let next = AtomicUsize::new(0);
+    let mut results = std::thread::scope(|scope| {
+        let workers = (0..concurrency.min(items.len()))
+            .map(|_| {
+                scope.spawn(|| {
+                    let mut results = vec![];
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        match items.get(index) {
+                            Some(item) => results.push((index, f(item))),
+                            None => break results,
+                        }
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+    results.sort_by_key(|(index, _result)| *index);
+    results.into_iter().map(|(_index, result)| result).collect()
+}
+
+// history is cached for good, so only what's finalized is fetched, rather than at the confirmed
+// commitment of the endpoints, which may still be rolled back
+fn history_commitment() -> Option<CommitmentConfig> {
+    Some(CommitmentConfig::finalized())
+}
+
+#[allow(deprecated)]
+fn transaction_config() -> solana_client::rpc_deprecated_config::RpcConfirmedTransactionConfig {
+    solana_client::rpc_deprecated_config::RpcConfirmedTransactionConfig {
+        encoding: Some(UiTransactionEncoding::Base64),
+        commitment: history_commitment(),
+    }
+}
+
+/// The whole signature history of the address, newest first, along with the
+/// endpoints which answered for it
+///
+/// With a cache, only the signatures newer than the cached ones are fetched.
+pub fn get_all_signatures(
+    endpoints: &Endpoints,
+    cache: Option<&Cache>,
+    throttle: &Throttle,
+    address: &Pubkey,
+) -> ClientResult<(
+    Vec<RpcConfirmedTransactionStatusWithSignature>,
+    BTreeSet<String>,
+)> {
+    let cached = cache.and_then(|cache| cache.get_signatures(address));
+    let was_cached = cached.is_some();
+    let CachedSignatures {
+        endpoints: mut urls,
+        signatures: cached_sigs,
+    } = cached.unwrap_or_default();
+    let until = cached_sigs
+        .first()
+        .and_then(|s| Signature::from_str(s.signature.as_str()).ok());
+    let mut all_sigs = vec![];
+    let mut before = Option::<Signature>::None;
+    loop {
+        let (sigs, url) = throttle.call(|| {
+            endpoints.try_each(Role::History, |rpc_client| {
+                let request_config = GetConfirmedSignaturesForAddress2Config {
+                    before,
+                    until,
+                    limit: Some(SIGNATURES_LIMIT),
+                    commitment: history_commitment(),
+                };
+                #[allow(deprecated)]
+                rpc_client
+                    .get_confirmed_signatures_for_address2_with_config(address, request_config)
+            })
+        })?;
+        urls.insert(url.to_string());
+
+        before = if sigs.len() < SIGNATURES_LIMIT {
+            None
+        } else {
+            sigs.last()
+                .and_then(|s| Signature::from_str(s.signature.as_str()).ok())
+        };
+        all_sigs.extend(sigs);
+
+        // last
+        if before.is_none() {
+            break;
+        }
+    }
+
+    let grew = !all_sigs.is_empty() || !was_cached;
+    all_sigs.extend(cached_sigs);
+    let cached = CachedSignatures {
+        endpoints: urls,
+        signatures: all_sigs,
+    };
+    if let Some(cache) = cache.filter(|_cache| grew) {
+        cache.put_signatures(address, &cached);
+    }
+    Ok((cached.signatures, cached.endpoints))
+}
+
+/// The successful transactions among the signatures, which failed ones are
+/// left out of since they didn't change anything
+pub fn successful_signatures(
+    sigs: &[RpcConfirmedTransactionStatusWithSignature],
+) -> impl Iterator<Item = (Signature, u64)> + Clone + '_ {
+    sigs.iter().filter_map(|sig_with_status| {
+        if sig_with_status.err.is_some() {
+            None
+        } else {
+            Some((
+                Signature::from_str(sig_with_status.signature.as_str()).ok()?,
+                sig_with_status.slot,
+            ))
+        }
+    })
+}
+
+/// The accounts at the addresses, in as few getMultipleAccounts requests as
+/// possible, `None` for those which don't exist
+pub fn get_multiple_accounts(
+    endpoints: &Endpoints,
+    addresses: &[Pubkey],
+) -> ClientResult<Vec<Option<Account>>> {
+    let mut accounts = Vec::with_capacity(addresses.len());
+    for addresses in addresses.chunks(MULTIPLE_ACCOUNTS_LIMIT) {
+        accounts.extend(endpoints.call(Role::Accounts, |rpc_client| {
+            rpc_client.get_multiple_accounts(addresses)
+        })?);
+    }
+    Ok(accounts)
+}
+
+/// Fetch the transactions in batch requests on up to `concurrency` threads, in
+/// the order of the signatures, along with the endpoint which answered for each
+///
+/// With a cache, the cached transactions aren't fetched at all, and the others
+/// are cached once fetched.
+pub fn get_transactions(
+    endpoints: &Endpoints,
+    cache: Option<&Cache>,
+    throttle: &Throttle,
+    concurrency: usize,
+    signatures: &[Signature],
+) -> Vec<ClientResult<(EncodedConfirmedTransaction, String)>> {
+    let cached = signatures
+        .iter()
+        .map(|signature| cache.and_then(|cache| cache.get_transaction(signature)))
+        .collect::<Vec<_>>();
+    let missing = signatures
+        .iter()
+        .zip(&cached)
+        .filter(|(_signature, cached)| cached.is_none())
+        .map(|(signature, _cached)| *signature)
+        .collect::<Vec<_>>();
+    let batches = missing.chunks(TRANSACTIONS_PER_BATCH).collect::<Vec<_>>();
+    let mut fetched = parallel_map(concurrency, &batches, |batch| {
+        get_transaction_batch(endpoints, throttle, batch)
+    })
+    .into_iter()
+    .flatten()
+    .zip(&missing);
+    cached
+        .into_iter()
+        .map(|cached| {
+            let cached = match cached {
+                Some(cached) => cached,
+                None => {
+                    let (result, signature) = fetched.next().unwrap();
+                    let (transaction, url) = result?;
+                    let cached = CachedTransaction {
+                        endpoint: url,
+                        transaction,
+                    };
+                    if let Some(cache) = cache {
+                        cache.put_transaction(signature, &cached);
+                    }
+                    cached
+                }
+            };
+            Ok((cached.transaction, cached.endpoint))
+        })
+        .collect()
+}
+
+// the transactions a batch doesn't bring, or all of them if the endpoints don't take batches, are
+// fetched on their own, retrying and failing over as usual
+fn get_transaction_batch(
+    endpoints: &Endpoints,
+    throttle: &Throttle,
+    signatures: &[Signature],
+) -> Vec<ClientResult<(EncodedConfirmedTransaction, String)>> {
+    let params = signatures
+        .iter()
+        .map(|signature| json!([signature.to_string(), transaction_config()]))
+        .collect::<Vec<_>>();
+    let answers = match throttle.call(|| {
+        endpoints.try_each_endpoint(Role::History, |endpoint| {
+            #[allow(deprecated)]
+            endpoint.send_batch(RpcRequest::GetConfirmedTransaction, &params)
+        })
+    }) {
+        Ok((answers, url)) => answers
+            .into_iter()
+            .map(|answer| answer.ok().map(|answer| (answer, url)))
+            .collect(),
+        Err(_) => vec![None; signatures.len()],
+    };
+    signatures
+        .iter()
+        .zip(answers)
+        .map(|(signature, answer)| {
+            if let Some((answer, url)) = answer {
+                if let Ok(transaction) = serde_json::from_value(answer) {
+                    return Ok((transaction, url.to_string()));
+                }
+            }
+            let (transaction, url) = throttle.call(|| {
+                endpoints.try_each(Role::History, |rpc_client| {
+                    #[allow(deprecated)]
+                    rpc_client
+                        .get_confirmed_transaction_with_config(signature, transaction_config())
+                })
+            })?;
+            Ok((transaction, url.to_string()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_transient_errors() {
+        let attempts = AtomicUsize::new(0);
+        let result = retry(|| {
+            if attempts.fetch_add(1, Ordering::SeqCst) == 0 {
+                Err(
+                    ClientErrorKind::Io(std::io::Error::from(std::io::ErrorKind::ConnectionReset))
+                        .into(),
+                )
+            } else {
+                Ok(42)
+            }
+        });
+        assert_eq!(result.unwrap(), 42);
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+
+        // not worth sending again
+        let attempts = AtomicUsize::new(0);
+        let result = retry(|| -> ClientResult<()> {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            Err(ClientErrorKind::RpcError(RpcError::ForUser("AccountNotFound".to_string())).into())
+        });
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn rejected_methods() {
+        let response_error = |code| {
+            ClientErrorKind::RpcError(RpcError::RpcResponseError {
+                code,
+                message: String::new(),
+                data: solana_client::rpc_request::RpcResponseErrorData::Empty,
+            })
+        };
+        assert!(is_rejected(&response_error(JSON_RPC_METHOD_NOT_FOUND)));
+        assert!(is_rejected(&response_error(
+            JSON_RPC_SERVER_ERROR_KEY_EXCLUDED_FROM_SECONDARY_INDEX
+        )));
+        assert!(!is_rejected(&response_error(
+            JSON_RPC_SERVER_ERROR_NODE_UNHEALTHY
+        )));
+        assert!(!is_rejected(&ClientErrorKind::Io(std::io::Error::from(
+            std::io::ErrorKind::TimedOut
+        ))));
+    }
+
+    #[test]
+    fn retry_pauses_grow() {
+        for retry in 0..RETRIES {
+            let longest = RETRY_PAUSE
+                .saturating_mul(2u32.pow(retry))
+                .min(LONGEST_RETRY_PAUSE);
+            let pause = retry_pause(retry);
+            assert!(pause >= longest / 2 && pause <= longest, "{:?}", pause);
+        }
+    }
+
+    #[test]
+    fn parallel_map_keeps_order() {
+        let items = (0..100).collect::<Vec<u64>>();
+        let results = parallel_map(8, &items, |item| {
+            // finish out of order
+            std::thread::sleep(Duration::from_micros(100 - item));
+            item * 2
+        });
+        assert_eq!(
+            results,
+            items.iter().map(|item| item * 2).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn throttle_bounds_concurrency() {
+        let throttle = Throttle::new(3);
+        let active = AtomicUsize::new(0);
+        let max_active = AtomicUsize::new(0);
+        let items = (0..50).collect::<Vec<_>>();
+        parallel_map(10, &items, |_item| {
+            throttle
+                .call(|| {
+                    let now_active = active.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_active.fetch_max(now_active, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(1));
+                    active.fetch_sub(1, Ordering::SeqCst);
+                    Ok(())
+                })
+                .unwrap()
+        });
+        assert!(max_active.load(Ordering::SeqCst) <= 3);
+    }
+
+    #[test]
+    fn fetch_transactions_without_batches() {
+        use {
+            solana_client::rpc_client::RpcClient,
+            solana_transaction_status::{EncodedTransaction, EncodedTransactionWithStatusMeta},
+        };
+        let mut mocks = std::collections::HashMap::new();
+        mocks.insert(
+            #[allow(deprecated)]
+            RpcRequest::GetConfirmedTransaction,
+            serde_json::to_value(EncodedConfirmedTransaction {
+                slot: 42,
+                transaction: EncodedTransactionWithStatusMeta {
+                    transaction: EncodedTransaction::LegacyBinary("1".to_string()),
+                    meta: None,
+                },
+                block_time: None,
+            })
+            .unwrap(),
+        );
+        // the mock isn't reachable over HTTP, so the batch fails and the transaction is fetched
+        // on its own
+        let endpoints = Endpoints::single(
+            "mock".to_string(),
+            RpcClient::new_mock_with_mocks("mock".to_string(), mocks),
+        );
+        let mut results = get_transactions(
+            &endpoints,
+            None,
+            &Throttle::new(1),
+            1,
+            &[Signature::new(&[1; 64])],
+        );
+        let (transaction, url) = results.remove(0).unwrap();
+        assert_eq!((transaction.slot, url.as_str()), (42, "mock"));
+    }
+
+    #[test]
+    fn history_is_finalized() {
+        assert_eq!(
+            serde_json::to_value(transaction_config()).unwrap(),
+            json!({"encoding": "base64", "commitment": "finalized"})
+        );
+    }
+
+    #[test]
+    fn throttle_adapts_to_rate_limits() {
+        let throttle = Throttle::new(8);
+        throttle.acquire();
+        throttle.release(/* rate_limited = */ true);
+        assert_eq!(throttle.limit(), 4);
+        throttle.acquire();
+        throttle.release(true);
+        assert_eq!(throttle.limit(), 2);
+        // back up by one after as many successes as the limit
+        for _ in 0..2 {
+            throttle.acquire();
+            throttle.release(false);
+        }
+        assert_eq!(throttle.limit(), 3);
+        for _ in 0..100 {
+            throttle.acquire();
+            throttle.release(false);
+        }
+        assert_eq!(throttle.limit(), 8);
+    }
+}
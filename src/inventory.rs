@@ -0,0 +1,343 @@
+use {
+    crate::{
+        config::Config,
+        fetch,
+        owners::{csv_field, OwnerMetadata},
+    },
+    solana_account_decoder::parse_token::token_amount_to_ui_amount,
+    solana_sdk::{program_option::COption, program_pack::Pack, pubkey::Pubkey, signature::Signer},
+    spl_token::state::{Account, Mint},
+    std::{
+        collections::{BTreeMap, BTreeSet, HashMap},
+        io::Write,
+    },
+};
+
+fn format_amount(amount: u64, decimals: Option<u8>) -> String {
+    // raw amounts if the mint couldn't be fetched
+    match decimals {
+        Some(decimals) => token_amount_to_ui_amount(amount, decimals).ui_amount_string,
+        None => amount.to_string(),
+    }
+}
+
+fn format_option(pubkey: COption<Pubkey>) -> String {
+    match pubkey {
+        COption::Some(pubkey) => pubkey.to_string(),
+        COption::None => String::new(),
+    }
+}
+
+fn join_pubkeys(pubkeys: &BTreeSet<Pubkey>) -> String {
+    pubkeys
+        .iter()
+        .map(|pubkey| pubkey.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Current token accounts of a set of owners, without any transaction history
+#[derive(Debug, Default)]
+pub struct Inventory {
+    // accounts by owner and mint, then by address
+    pub accounts: BTreeMap<(Pubkey, Pubkey), BTreeMap<Pubkey, Account>>,
+    // decimals of each mint, `None` if it couldn't be fetched
+    pub mint_decimals: HashMap<Pubkey, Option<u8>>,
+    // the mints the organization supports, if given
+    pub supported_mints: Option<Vec<Pubkey>>,
+    pub owner_metadata: OwnerMetadata,
+    // owners whose accounts couldn't be listed, with the error, so some accounts may be missing
+    pub incomplete_owners: BTreeMap<Pubkey, String>,
+}
+
+impl Inventory {
+    pub fn add(&mut self, owner: Pubkey, address: Pubkey, account: Account) {
+        self.accounts
+            .entry((owner, account.mint))
+            .or_default()
+            .insert(address, account);
+    }
+
+    // empty without a list of supported mints
+    fn support(&self, mint: &Pubkey) -> &'static str {
+        match &self.supported_mints {
+            Some(supported_mints) if supported_mints.contains(mint) => "supported",
+            Some(_) => "unsupported",
+            None => "",
+        }
+    }
+
+    fn decimals(&self, mint: &Pubkey) -> Option<u8> {
+        self.mint_decimals.get(mint).copied().flatten()
+    }
+
+    /// Output the inventory as csv, totalled per owner and mint, then per
+    /// account
+    ///
+    /// Mints outside of the supported list are flagged as unsupported, and
+    /// listed on their own first, since a cleanup restricted to the supported
+    /// mints leaves their delegations in place.
+    pub fn write<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        let metadata_columns = OwnerMetadata::format_values(&self.owner_metadata.columns);
+
+        if self.supported_mints.is_some() {
+            let mut unsupported = BTreeMap::<Pubkey, (usize, usize, u64)>::new();
+            for ((_owner, mint), accounts) in &self.accounts {
+                if self.support(mint) != "unsupported" {
+                    continue;
+                }
+                let total = unsupported.entry(*mint).or_default();
+                total.0 += 1;
+                total.1 += accounts.len();
+                for account in accounts.values() {
+                    total.2 = total.2.saturating_add(account.amount);
+                }
+            }
+            writeln!(&mut writer, "Unsupported Mints")?;
+            writeln!(&mut writer, "Mint,Owners,Accounts,Balance")?;
+            for (mint, (owners, accounts, balance)) in &unsupported {
+                writeln!(
+                    &mut writer,
+                    "{},{},{},{}",
+                    mint,
+                    owners,
+                    accounts,
+                    format_amount(*balance, self.decimals(mint))
+                )?;
+            }
+            writeln!(&mut writer)?;
+        }
+
+        writeln!(&mut writer, "Inventory Per Owner And Mint")?;
+        writeln!(
+            &mut writer,
+            "Support,Owner Address,Mint,Accounts,Balance,Delegated Accounts,Delegates,Delegated Amount,Close Authorities{}",
+            metadata_columns
+        )?;
+        for ((owner, mint), accounts) in &self.accounts {
+            let mut balance = 0u64;
+            let mut delegated_accounts = 0;
+            let mut delegates = BTreeSet::new();
+            let mut delegated_amount = 0u64;
+            let mut close_authorities = BTreeSet::new();
+            for account in accounts.values() {
+                balance = balance.saturating_add(account.amount);
+                if let COption::Some(delegate) = account.delegate {
+                    delegated_accounts += 1;
+                    delegates.insert(delegate);
+                    delegated_amount = delegated_amount.saturating_add(account.delegated_amount);
+                }
+                if let COption::Some(close_authority) = account.close_authority {
+                    close_authorities.insert(close_authority);
+                }
+            }
+            let decimals = self.decimals(mint);
+            writeln!(
+                &mut writer,
+                "{},{},{},{},{},{},{},{},{}{}",
+                self.support(mint),
+                owner,
+                mint,
+                accounts.len(),
+                format_amount(balance, decimals),
+                delegated_accounts,
+                join_pubkeys(&delegates),
+                format_amount(delegated_amount, decimals),
+                join_pubkeys(&close_authorities),
+                OwnerMetadata::format_values(&self.owner_metadata.values(owner))
+            )?;
+        }
+
+        writeln!(&mut writer)?;
+        writeln!(&mut writer, "Inventory Per Account")?;
+        writeln!(
+            &mut writer,
+            "Support,Owner Address,Mint,Account Address,Balance,Delegate,Delegated Amount,Close Authority{}",
+            metadata_columns
+        )?;
+        for ((owner, mint), accounts) in &self.accounts {
+            let decimals = self.decimals(mint);
+            for (address, account) in accounts {
+                writeln!(
+                    &mut writer,
+                    "{},{},{},{},{},{},{},{}{}",
+                    self.support(mint),
+                    owner,
+                    mint,
+                    address,
+                    format_amount(account.amount, decimals),
+                    format_option(account.delegate),
+                    format_amount(account.delegated_amount, decimals),
+                    format_option(account.close_authority),
+                    OwnerMetadata::format_values(&self.owner_metadata.values(owner))
+                )?;
+            }
+        }
+
+        writeln!(&mut writer)?;
+        writeln!(&mut writer, "Incomplete Owners")?;
+        writeln!(&mut writer, "Owner Address,Error{}", metadata_columns)?;
+        for (owner, error) in &self.incomplete_owners {
+            writeln!(
+                &mut writer,
+                "{},{}{}",
+                owner,
+                csv_field(error),
+                OwnerMetadata::format_values(&self.owner_metadata.values(owner))
+            )?;
+        }
+        Ok(())
+    }
+}
+
+pub fn run(
+    config: Config,
+    owners: Vec<Box<dyn Signer>>,
+    supported_mints: Option<Vec<Pubkey>>,
+    owner_metadata: OwnerMetadata,
+) {
+    println!("inventory");
+    let mut inventory = Inventory {
+        supported_mints,
+        owner_metadata,
+        ..Inventory::default()
+    };
+    // every mint, so that the unsupported ones show up
+    let failed_owners = crate::for_all_spl_token_accounts(
+        &config,
+        owners.as_slice(),
+        None,
+        |_config, owner, address, account| inventory.add(owner.pubkey(), *address, *account),
+    );
+    for (owner, err) in failed_owners {
+        inventory
+            .incomplete_owners
+            .insert(owner, format!("couldn't list the token accounts: {}", err));
+    }
+
+    let mints = inventory
+        .accounts
+        .keys()
+        .map(|(_owner, mint)| *mint)
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect::<Vec<_>>();
+    let mint_accounts =
+        fetch::get_multiple_accounts(&config.endpoints, &mints).unwrap_or_else(|err| {
+            eprintln!("couldn't fetch the mints: {}", err);
+            vec![None; mints.len()]
+        });
+    for (mint, mint_account) in mints.into_iter().zip(mint_accounts) {
+        let decimals = mint_account
+            .and_then(|mint_account| Mint::unpack(&mint_account.data).ok())
+            .map(|mint| mint.decimals);
+        if decimals.is_none() {
+            eprintln!("couldn't fetch mint {}, its amounts will be raw", mint);
+        }
+        inventory.mint_decimals.insert(mint, decimals);
+    }
+
+    inventory.write(std::io::stdout()).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use {super::*, spl_token::state::AccountState};
+
+    fn new_account(mint: Pubkey, owner: Pubkey, amount: u64) -> Account {
+        Account {
+            mint,
+            owner,
+            amount,
+            state: AccountState::Initialized,
+            ..Account::default()
+        }
+    }
+
+    #[test]
+    fn inventory_per_owner_and_mint() {
+        let owner = Pubkey::new_unique();
+        let supported_mint = Pubkey::new_unique();
+        let unsupported_mint = Pubkey::new_unique();
+        let delegate = Pubkey::new_unique();
+        let close_authority = Pubkey::new_unique();
+        let mut inventory = Inventory {
+            supported_mints: Some(vec![supported_mint]),
+            ..Inventory::default()
+        };
+        inventory.mint_decimals.insert(supported_mint, Some(2));
+        inventory.add(
+            owner,
+            Pubkey::new_unique(),
+            new_account(supported_mint, owner, 150),
+        );
+        inventory.add(
+            owner,
+            Pubkey::new_unique(),
+            Account {
+                delegate: COption::Some(delegate),
+                delegated_amount: 25,
+                close_authority: COption::Some(close_authority),
+                ..new_account(supported_mint, owner, 50)
+            },
+        );
+        let unsupported_address = Pubkey::new_unique();
+        inventory.add(
+            owner,
+            unsupported_address,
+            new_account(unsupported_mint, owner, 7),
+        );
+
+        let mut buffer: Vec<u8> = vec![];
+        inventory.write(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        assert!(converted.contains(&format!(
+            "supported,{},{},2,2,1,{},0.25,{}\n",
+            owner, supported_mint, delegate, close_authority
+        )));
+        // the mint couldn't be fetched, so the amounts are raw
+        assert!(converted.contains(&format!("{},1,1,7\n", unsupported_mint)));
+        assert!(converted.contains(&format!(
+            "unsupported,{},{},{},7,,0,\n",
+            owner, unsupported_mint, unsupported_address
+        )));
+    }
+
+    #[test]
+    fn inventory_without_supported_mints() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let mut inventory = Inventory::default();
+        inventory.add(owner, Pubkey::new_unique(), new_account(mint, owner, 1));
+
+        let mut buffer: Vec<u8> = vec![];
+        inventory.write(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        assert!(!converted.contains("Unsupported Mints"));
+        assert!(converted.contains(&format!(",{},{},1,1,0,,0,\n", owner, mint)));
+    }
+
+    #[test]
+    fn inventory_incomplete_owners() {
+        let owner = Pubkey::new_unique();
+        let mut inventory = Inventory::default();
+        inventory.owner_metadata.columns = vec!["customer_id".to_string()];
+        inventory
+            .owner_metadata
+            .values_by_owner
+            .insert(owner, vec!["42".to_string()]);
+        inventory.incomplete_owners.insert(
+            owner,
+            "couldn't list the token accounts: timed out, 30s".to_string(),
+        );
+
+        let mut buffer: Vec<u8> = vec![];
+        inventory.write(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        assert!(converted.contains("Incomplete Owners\nOwner Address,Error,customer_id\n"));
+        assert!(converted.contains(&format!(
+            "{},\"couldn't list the token accounts: timed out, 30s\",42\n",
+            owner
+        )));
+    }
+}
@@ -1,30 +1,132 @@
+// ClientError is large, but it's what the RPC client returns, so every result built on it is too
+#![allow(clippy::result_large_err)]
+
 use {
-    crate::config::Config,
+    crate::{config::Config, endpoints::Role, fetch::is_rejected},
+    serde_json::json,
     solana_account_decoder::UiAccountEncoding,
     solana_client::{
-        client_error::Result as ClientResult,
-        rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig},
+        client_error::{ClientError, Result as ClientResult},
+        rpc_client::RpcClient,
+        rpc_config::{RpcAccountInfoConfig, RpcProgramAccountsConfig, RpcTokenAccountsFilter},
         rpc_filter::{Memcmp, MemcmpEncodedBytes, RpcFilterType},
+        rpc_request::RpcRequest,
+        rpc_response::{Response as RpcResponse, RpcKeyedAccount},
     },
     solana_sdk::{program_pack::Pack, pubkey::Pubkey, signer::Signer},
+    std::str::FromStr,
 };
 
 pub mod audit;
+pub mod cache;
 pub mod cleanup;
 pub mod config;
+pub mod derivation;
+pub mod endpoints;
+pub mod fetch;
+pub mod inventory;
+pub mod owners;
 pub mod report;
 pub mod token;
 
+// byte offsets of the fields to filter token accounts on
+const MINT_OFFSET: usize = 0;
+const OWNER_OFFSET: usize = 32;
+
+/// All spl-token accounts with the given key at the given offset
+fn get_spl_token_accounts(
+    rpc_client: &RpcClient,
+    offset: usize,
+    key: &Pubkey,
+) -> ClientResult<Vec<(Pubkey, spl_token::state::Account)>> {
+    let filters = Some(vec![
+        RpcFilterType::DataSize(spl_token::state::Account::LEN as u64),
+        RpcFilterType::Memcmp(Memcmp {
+            offset,
+            bytes: MemcmpEncodedBytes::Binary(bs58::encode(key).into_string()),
+            encoding: None,
+        }),
+    ]);
+    let account_config = RpcAccountInfoConfig {
+        encoding: Some(UiAccountEncoding::Base64),
+        ..RpcAccountInfoConfig::default()
+    };
+    let get_program_accounts_config = RpcProgramAccountsConfig {
+        filters,
+        account_config,
+        ..RpcProgramAccountsConfig::default()
+    };
+
+    Ok(rpc_client
+        .get_program_accounts_with_config(&spl_token::id(), get_program_accounts_config)?
+        .into_iter()
+        .filter_map(|(addr, acct)| {
+            let token_account = spl_token::state::Account::unpack(&acct.data).ok();
+            if token_account.is_none() {
+                eprintln!("unexpected account data at {}:", addr);
+            }
+            Some(addr).zip(token_account)
+        })
+        .collect())
+}
+
+/// All spl-token accounts of the owner, with one getTokenAccountsByOwner call
+/// per mint if given, or for the whole program otherwise
+fn get_spl_token_accounts_by_owner(
+    rpc_client: &RpcClient,
+    owner: &Pubkey,
+    mints: Option<&[Pubkey]>,
+) -> ClientResult<Vec<(Pubkey, spl_token::state::Account)>> {
+    let token_account_filters = match mints {
+        Some(mints) => mints
+            .iter()
+            .map(|mint| RpcTokenAccountsFilter::Mint(mint.to_string()))
+            .collect(),
+        None => vec![RpcTokenAccountsFilter::ProgramId(
+            spl_token::id().to_string(),
+        )],
+    };
+    let account_config = RpcAccountInfoConfig {
+        encoding: Some(UiAccountEncoding::Base64),
+        commitment: Some(rpc_client.commitment()),
+        ..RpcAccountInfoConfig::default()
+    };
+
+    let mut accounts = vec![];
+    for token_account_filter in token_account_filters {
+        let response: RpcResponse<Vec<RpcKeyedAccount>> = rpc_client.send(
+            RpcRequest::GetTokenAccountsByOwner,
+            json!([owner.to_string(), token_account_filter, account_config]),
+        )?;
+        accounts.extend(response.value.into_iter().filter_map(|keyed_account| {
+            let addr = Pubkey::from_str(&keyed_account.pubkey).ok()?;
+            let token_account = keyed_account
+                .account
+                .decode::<solana_sdk::account::Account>()
+                .and_then(|acct| spl_token::state::Account::unpack(&acct.data).ok());
+            if token_account.is_none() {
+                eprintln!("unexpected account data at {}:", addr);
+            }
+            Some(addr).zip(token_account)
+        }));
+    }
+    Ok(accounts)
+}
+
+/// Call `f` for every spl-token account of the wallets, returning the wallets
+/// whose accounts couldn't be listed, which are skipped
 pub fn for_all_spl_token_accounts<F>(
     config: &Config,
     wallets: &[Box<dyn Signer>],
     mints: Option<&[Pubkey]>,
     mut f: F,
-) -> ClientResult<()>
+) -> Vec<(Pubkey, ClientError)>
 where
     F: FnMut(&Config, &dyn Signer, &Pubkey, &spl_token::state::Account),
 {
     let mut already_processed = std::collections::HashSet::new();
+    let mut use_program_accounts = !config.token_accounts_by_owner;
+    let mut failed_wallets = vec![];
 
     for wallet in wallets {
         let wallet_pubkey = wallet.pubkey();
@@ -34,35 +136,42 @@ where
             already_processed.insert(wallet_pubkey);
         }
 
-        let filters = Some(vec![
-            RpcFilterType::DataSize(spl_token::state::Account::LEN as u64),
-            RpcFilterType::Memcmp(Memcmp {
-                offset: 32,
-                bytes: MemcmpEncodedBytes::Binary(bs58::encode(wallet_pubkey).into_string()),
-                encoding: None,
+        let program_accounts = if use_program_accounts {
+            match config.endpoints.call(Role::Accounts, |rpc_client| {
+                get_spl_token_accounts(rpc_client, OWNER_OFFSET, &wallet_pubkey)
+            }) {
+                Err(err) if is_rejected(&err.kind) => {
+                    eprintln!(
+                        "getProgramAccounts was rejected, falling back to getTokenAccountsByOwner: {}",
+                        err
+                    );
+                    use_program_accounts = false;
+                    None
+                }
+                result => Some(result),
+            }
+        } else {
+            None
+        };
+        let accounts = match program_accounts {
+            Some(accounts) => accounts,
+            None => config.endpoints.call(Role::Accounts, |rpc_client| {
+                get_spl_token_accounts_by_owner(rpc_client, &wallet_pubkey, mints)
             }),
-        ]);
-        let account_config = RpcAccountInfoConfig {
-            encoding: Some(UiAccountEncoding::Base64),
-            ..RpcAccountInfoConfig::default()
         };
-        let get_program_accounts_config = RpcProgramAccountsConfig {
-            filters,
-            account_config,
-            ..RpcProgramAccountsConfig::default()
+        let accounts = match accounts {
+            Ok(accounts) => accounts,
+            Err(err) => {
+                eprintln!(
+                    "couldn't list the token accounts of {}, skipping: {}",
+                    wallet_pubkey, err
+                );
+                failed_wallets.push((wallet_pubkey, err));
+                continue;
+            }
         };
-
-        config
-            .rpc_client
-            .get_program_accounts_with_config(&spl_token::id(), get_program_accounts_config)?
+        accounts
             .into_iter()
-            .filter_map(|(addr, acct)| {
-                let token_account = spl_token::state::Account::unpack(&acct.data).ok();
-                if token_account.is_none() {
-                    eprintln!("unexpected account data at {}:", addr);
-                }
-                Some(addr).zip(token_account)
-            })
             .filter(|(_address, account)| {
                 mints
                     .map(|mints| mints.contains(&account.mint))
@@ -71,15 +180,113 @@ where
             .map(|(address, account)| (config, wallet.as_ref(), address, account))
             .for_each(|(config, wallet, address, account)| f(config, wallet, &address, &account));
     }
+    failed_wallets
+}
+
+/// Like `for_all_spl_token_accounts`, but for every holder of the mint
+pub fn for_all_spl_token_accounts_of_mint<F>(
+    config: &Config,
+    mint: &Pubkey,
+    mut f: F,
+) -> ClientResult<()>
+where
+    F: FnMut(&Config, &Pubkey, &spl_token::state::Account),
+{
+    config
+        .endpoints
+        .call(Role::Accounts, |rpc_client| {
+            get_spl_token_accounts(rpc_client, MINT_OFFSET, mint)
+        })?
+        .into_iter()
+        .for_each(|(address, account)| f(config, &address, &account));
     Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use solana_client::rpc_client::RpcClient;
+    use crate::endpoints::Endpoints;
+    use solana_account_decoder::UiAccount;
+    use solana_client::rpc_response::RpcResponseContext;
     use solana_sdk::signer::null_signer::NullSigner;
-    use std::str::FromStr;
+
+    #[test]
+    fn test_token_accounts_by_owner() {
+        let wallet = Pubkey::new_unique();
+        let address = Pubkey::new_unique();
+        let account = spl_token::state::Account {
+            mint: Pubkey::new_unique(),
+            owner: wallet,
+            amount: 42,
+            state: spl_token::state::AccountState::Initialized,
+            ..spl_token::state::Account::default()
+        };
+        let mut data = vec![0; spl_token::state::Account::LEN];
+        spl_token::state::Account::pack(account, &mut data).unwrap();
+        let ui_account = UiAccount::encode(
+            &address,
+            &solana_sdk::account::Account {
+                lamports: 1,
+                data,
+                owner: spl_token::id(),
+                executable: false,
+                rent_epoch: 0,
+            },
+            UiAccountEncoding::Base64,
+            None,
+            None,
+        );
+        let mut mocks = std::collections::HashMap::new();
+        mocks.insert(
+            RpcRequest::GetTokenAccountsByOwner,
+            serde_json::to_value(RpcResponse {
+                context: RpcResponseContext { slot: 1 },
+                value: vec![RpcKeyedAccount {
+                    pubkey: address.to_string(),
+                    account: ui_account,
+                }],
+            })
+            .unwrap(),
+        );
+        let mut config = Config {
+            endpoints: Endpoints::single(
+                "mock".to_string(),
+                RpcClient::new_mock_with_mocks("mock".to_string(), mocks.clone()),
+            ),
+            fee_payer: Box::new(NullSigner::new(&wallet)),
+            dry_run: true,
+            verbose: false,
+            token_accounts_by_owner: false,
+            concurrency: 1,
+            cache: None,
+        };
+
+        // the mock has no answer for getProgramAccounts, which is a failure rather than a
+        // rejection, so the wallet is skipped instead of falling back
+        let mut found = vec![];
+        let failed_wallets = for_all_spl_token_accounts(
+            &config,
+            &[Box::new(NullSigner::new(&wallet))],
+            None,
+            |_config, _wallet, address, account| found.push((*address, *account)),
+        );
+        assert_eq!(failed_wallets.len(), 1);
+        assert!(found.is_empty());
+
+        config.token_accounts_by_owner = true;
+        config.endpoints = Endpoints::single(
+            "mock".to_string(),
+            RpcClient::new_mock_with_mocks("mock".to_string(), mocks),
+        );
+        let failed_wallets = for_all_spl_token_accounts(
+            &config,
+            &[Box::new(NullSigner::new(&wallet))],
+            None,
+            |_config, _wallet, address, account| found.push((*address, *account)),
+        );
+        assert!(failed_wallets.is_empty());
+        assert_eq!(found, vec![(address, account)]);
+    }
 
     #[test]
     fn test_for_all_spl_token_accounts() {
@@ -89,17 +296,19 @@ mod tests {
             &Pubkey::from_str("EriSViggFFQ72fYgCKYyattiY3rDsx9bnMgMUpGa5x2H").unwrap(),
         ));
         let config = Config {
-            json_rpc_url,
-            rpc_client,
+            endpoints: Endpoints::single(json_rpc_url, rpc_client),
             fee_payer,
             dry_run: true,
             verbose: true,
+            token_accounts_by_owner: false,
+            concurrency: 1,
+            cache: None,
         };
         let wallet = NullSigner::new(
             &Pubkey::from_str("EriSViggFFQ72fYgCKYyattiY3rDsx9bnMgMUpGa5x2H").unwrap(),
         );
         let mint = Pubkey::from_str("4k3Dyjzvzp8eMZWUXbBCjEvwSkkk59S5iCNLY3QrkX6R").unwrap();
-        for_all_spl_token_accounts(
+        let failed_wallets = for_all_spl_token_accounts(
             &config,
             &[Box::new(wallet)],
             Some(&[mint]),
@@ -111,7 +320,7 @@ mod tests {
                     account
                 );
             },
-        )
-        .unwrap();
+        );
+        assert!(failed_wallets.is_empty());
     }
 }
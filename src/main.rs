@@ -1,19 +1,31 @@
 use {
     clap::{Arg, ArgMatches},
-    inc_20210825::{audit, cleanup},
+    inc_20210825::{
+        audit,
+        cache::Cache,
+        cleanup,
+        derivation::{self, DerivationRange},
+        endpoints::{is_endpoint_list, Endpoints, Role},
+        fetch, inventory,
+        owners::{OwnerKind, OwnerMetadata, OwnersFile},
+    },
     solana_clap_utils::{
-        input_validators::{
-            is_url_or_moniker, is_valid_pubkey, is_valid_signer, normalize_to_url_if_moniker,
-        },
+        input_validators::{is_valid_pubkey, is_valid_signer},
         keypair::{signer_from_path, signer_from_path_with_config, SignerFromPathConfig},
     },
-    solana_client::rpc_client::RpcClient,
     solana_remote_wallet::remote_wallet::RemoteWalletManager,
     solana_sdk::{
-        commitment_config::CommitmentConfig, program_pack::Pack, pubkey::Pubkey, signature::Signer,
-        system_program,
+        program_pack::Pack,
+        pubkey::Pubkey,
+        signature::{Signature, Signer},
+    },
+    std::{
+        collections::{HashMap, HashSet},
+        process::exit,
+        str::FromStr,
+        sync::Arc,
+        time::Duration,
     },
-    std::{process::exit, sync::Arc},
 };
 
 pub fn owner_keypair_arg<'a, 'b>() -> Arg<'a, 'b> {
@@ -25,6 +37,62 @@ pub fn owner_keypair_arg<'a, 'b>() -> Arg<'a, 'b> {
         .help("Keypair or address of the token's owner")
 }
 
+pub fn owners_file_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("owners_file")
+        .long("owners-file")
+        .takes_value(true)
+        .value_name("PATH")
+        .help(
+            "CSV or JSON lines (.jsonl) file of owner keypairs or addresses, with optional \
+             columns such as a customer ID or wallet label, which are carried into the report. \
+             CSV files need a header row and the owners in the first column, JSON lines need an \
+             \"owner\" key on each line",
+        )
+}
+
+pub fn seed_phrase_file_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("seed_phrase_file")
+        .long("seed-phrase-file")
+        .takes_value(true)
+        .value_name("PATH")
+        .requires("derivation_path_template")
+        .help(
+            "File holding the BIP39 seed phrase of the derived owners on its first line, and \
+             optionally the passphrase on the second one. Prompted for when not given",
+        )
+}
+
+pub fn derivation_path_template_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("derivation_path_template")
+        .long("derivation-path-template")
+        .takes_value(true)
+        .value_name("TEMPLATE")
+        .requires("derivation_range")
+        .help(
+            "Derive owners from a seed phrase over a range of indexes, substituting each index \
+             for {} in this key path under m/44'/501', such as \"{}/0\" or \"0/{}\"",
+        )
+}
+
+pub fn derivation_range_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("derivation_range")
+        .long("derivation-range")
+        .takes_value(true)
+        .value_name("START..END")
+        .requires("derivation_path_template")
+        .help("Indexes of the derived owners, from START up to but excluding END")
+}
+
+pub fn allow_non_system_owners_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("allow_non_system_owners")
+        .long("allow-non-system-owners")
+        .takes_value(false)
+        .help(
+            "Accept owners that aren't wallets of the system program, such as SPL token \
+             multisigs and program-derived addresses, which may be given by address",
+        )
+}
+
 pub fn mint_address_arg<'a, 'b>() -> Arg<'a, 'b> {
     Arg::with_name("mint")
         .long("mint")
@@ -50,12 +118,48 @@ fn get_signer(
         })
 }
 
+fn check_mints(endpoints: &Endpoints, mints: &[Pubkey]) {
+    let mint_accounts = fetch::get_multiple_accounts(endpoints, mints).unwrap_or_else(|e| {
+        eprintln!("error: couldn't fetch the mints: {}", e);
+        exit(1);
+    });
+    for (mint, mint_account) in mints.iter().zip(mint_accounts) {
+        let mint_account = mint_account.unwrap_or_else(|| {
+            eprintln!(
+                "Account {} expected to be an SPL token mint, but does not exist. Maybe this is a system account?",
+                mint
+            );
+            exit(1);
+        });
+        if mint_account.owner != spl_token::id() {
+            eprintln!("Account {} is not owned by the SPL token program, actually owned by {}, likely this parameter is incorrect", mint, mint_account.owner);
+            exit(1);
+        }
+        let _ = spl_token::state::Mint::unpack(&mint_account.data).unwrap_or_else(|_| {
+            eprintln!(
+                "Account {} is not an SPL token mint, likely this parameter is incorrect",
+                mint
+            );
+            exit(1);
+        });
+    }
+}
+
+// the owners and mints given on the command line, once checked
+struct CheckedOwners {
+    owners: Vec<Box<dyn Signer>>,
+    mints: Option<Vec<Pubkey>>,
+    owner_metadata: OwnerMetadata,
+    owner_kinds: HashMap<Pubkey, OwnerKind>,
+}
+
 fn get_owners_and_mints(
     sub_matches: &ArgMatches<'_>,
     allow_null_signer: bool,
-    rpc_client: &RpcClient,
+    allow_non_system_owners: bool,
+    endpoints: &Endpoints,
     wallet_manager: &mut Option<Arc<RemoteWalletManager>>,
-) -> (Vec<Box<dyn Signer>>, Option<Vec<Pubkey>>) {
+) -> CheckedOwners {
     let mints = if sub_matches.is_present("mint") {
         let mints = sub_matches
             .values_of("mint")
@@ -70,68 +174,217 @@ fn get_owners_and_mints(
                 .pubkey()
             })
             .collect::<Vec<_>>();
-        for mint in &mints {
-            let mint_account = rpc_client.get_account(mint).unwrap_or_else(|_| {
-                eprintln!(
-                    "Account {} expected to be an SPL token mint, but does not exist. Maybe this is a system account?",
-                    mint
-                );
-                exit(1);
-            });
-            if mint_account.owner != spl_token::id() {
-                eprintln!("Account {} is not owned by the SPL token program, actually owned by {}, likely this parameter is incorrect", mint, mint_account.owner);
-                exit(1);
-            }
-            let _ = spl_token::state::Mint::unpack(&mint_account.data).unwrap_or_else(|_| {
-                eprintln!(
-                    "Account {} is not an SPL token mint, likely this parameter is incorrect",
-                    mint
-                );
-                exit(1);
-            });
-        }
+        check_mints(endpoints, &mints);
         Some(mints)
     } else {
         None
     };
 
-    let owners = sub_matches
+    // multisigs and program-derived addresses can't sign for themselves, so only their address
+    // is needed, but wallets given by address are still refused unless null signers are allowed
+    let mut owners_by_address = HashSet::new();
+    let mut get_owner_signer =
+        |path: &str, wallet_manager: &mut Option<Arc<RemoteWalletManager>>| {
+            if allow_non_system_owners && !allow_null_signer {
+                if let Ok(owner_address) = Pubkey::from_str(path) {
+                    owners_by_address.insert(owner_address);
+                }
+            }
+            get_signer(
+                sub_matches,
+                path,
+                wallet_manager,
+                allow_null_signer || allow_non_system_owners,
+            )
+        };
+    let mut owners = sub_matches
         .values_of("owner")
-        .unwrap()
-        .map(|p| get_signer(sub_matches, p, wallet_manager, allow_null_signer))
+        .into_iter()
+        .flatten()
+        .map(|p| get_owner_signer(p, wallet_manager))
         .collect::<Vec<_>>();
 
-    for owner in &owners {
-        let owner_address = owner.pubkey();
-        // Don't unwrap to allow possibly non-existent owner
-        // A non-existent owner just means a system account with no lamports,
-        // which is a valid sitation for an owner account.
-        if let Ok(owner_account) = rpc_client.get_account(&owner_address) {
-            if owner_account.owner == spl_token::id() {
-                eprintln!("Account {} is not owned by the system program, actually owned by the SPL token program. Maybe this is a mint?", &owner_address);
+    let mut owner_metadata = OwnerMetadata::default();
+    if let Some(owners_file) = sub_matches.value_of("owners_file") {
+        let owners_file = OwnersFile::load(owners_file).unwrap_or_else(|err| {
+            eprintln!("error: {}", err);
+            exit(1);
+        });
+        owner_metadata.columns = owners_file.columns;
+        for row in owners_file.rows {
+            let owner = get_owner_signer(&row.owner, wallet_manager);
+            owner_metadata
+                .values_by_owner
+                .insert(owner.pubkey(), row.values);
+            owners.push(owner);
+        }
+    }
+
+    if let Some(template) = sub_matches.value_of("derivation_path_template") {
+        let derived =
+            DerivationRange::new(template, sub_matches.value_of("derivation_range").unwrap())
+                .and_then(|derivation_range| {
+                    let seed = derivation::read_seed(sub_matches.value_of("seed_phrase_file"))?;
+                    derivation_range.derive(&seed)
+                })
+                .unwrap_or_else(|err| {
+                    eprintln!("error: {}", err);
+                    exit(1);
+                });
+        // the derivation path of each derived owner becomes one more column
+        for values in owner_metadata.values_by_owner.values_mut() {
+            values.push(String::new());
+        }
+        let mut values = vec![String::new(); owner_metadata.columns.len()];
+        owner_metadata.columns.push("derivation_path".to_string());
+        for (derivation_path, keypair) in derived {
+            values.push(derivation_path);
+            owner_metadata
+                .values_by_owner
+                .insert(keypair.pubkey(), values.clone());
+            values.pop();
+            owners.push(Box::new(keypair) as Box<dyn Signer>);
+        }
+    }
+
+    let owner_addresses = owners
+        .iter()
+        .map(|owner| owner.pubkey())
+        .collect::<Vec<_>>();
+    let owner_kinds = OwnerKind::fetch_all(endpoints, &owner_addresses).unwrap_or_else(|err| {
+        eprintln!("error: {}", err);
+        exit(1);
+    });
+    let mut checked_kinds = HashMap::new();
+    for (owner_address, owner_kind) in owner_addresses.into_iter().zip(owner_kinds) {
+        match &owner_kind {
+            Ok(OwnerKind::Wallet) => {
+                if owners_by_address.contains(&owner_address) {
+                    eprintln!(
+                        "error: {} is a wallet, its keypair is needed",
+                        owner_address
+                    );
+                    exit(1);
+                }
+            }
+            Ok(owner_kind) if !allow_non_system_owners => {
+                eprintln!(
+                    "Owner {}, not a system wallet, likely this parameter is incorrect, or else pass --allow-non-system-owners",
+                    owner_kind.describe(&owner_address)
+                );
                 exit(1);
-            } else if owner_account.owner != system_program::id() {
-                eprintln!("Account {} is not owned by the system program, actually owned by {}, this parameter is likely incorrect", &owner_address, owner_account.owner);
+            }
+            Ok(_) => {}
+            Err(err) => {
+                eprintln!("{}", err);
                 exit(1);
             }
         }
+        checked_kinds.insert(owner_address, owner_kind.unwrap());
     }
 
-    (owners, mints)
+    CheckedOwners {
+        owners,
+        mints,
+        owner_metadata,
+        owner_kinds: checked_kinds,
+    }
 }
 
-fn check_rpc_has_genesis_block(rpc_client: &RpcClient, rpc_url: &str) {
-    let rpc_first_slot = rpc_client.get_first_available_block().unwrap_or_else(|_| {
-        eprintln!("Could not fetch available block from {}, perhaps the URL is wrong or the backend is down", rpc_url);
-        exit(1);
-    });
+// history queries only go to the endpoints which have the genesis block, if any does
+fn check_rpc_has_genesis_block(endpoints: &mut Endpoints) {
     const FIRST_SLOT: u64 = 1; // solana-test-validator starts at slot 1, so this makes testing easier
-    if rpc_first_slot > FIRST_SLOT {
+    let mut partial_history = vec![];
+    for (index, endpoint) in endpoints.endpoints.iter().enumerate() {
+        if !endpoint.role.serves(Role::History) {
+            continue;
+        }
+        match endpoint.rpc_client.get_first_available_block() {
+            Ok(rpc_first_slot) if rpc_first_slot <= FIRST_SLOT => continue,
+            Ok(rpc_first_slot) => eprintln!(
+                "Earliest block from {} is {}, some transactions will be omitted during audit",
+                endpoint.name, rpc_first_slot
+            ),
+            Err(_) => eprintln!("Could not fetch available block from {}, perhaps the URL is wrong or the backend is down", endpoint.name),
+        }
+        // by index rather than URL, which may hold an API key
+        partial_history.push(index);
+    }
+    if partial_history.len() == endpoints.serving(Role::History).count() {
+        exit(1);
+    }
+    for index in &partial_history {
         eprintln!(
-            "Earliest block from {} is {}, some transactions will be omitted during audit",
-            rpc_url, rpc_first_slot
+            "Sending history queries to the other endpoints than {}",
+            endpoints.endpoints[*index].name
         );
-        exit(1);
+    }
+    endpoints.endpoints = std::mem::take(&mut endpoints.endpoints)
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, mut endpoint)| {
+            if partial_history.contains(&index) {
+                if endpoint.role == Role::History {
+                    return None;
+                }
+                endpoint.role = Role::Accounts;
+            }
+            Some(endpoint)
+        })
+        .collect();
+}
+
+fn run_cache(cache: &Cache, sub_matches: &ArgMatches) {
+    match sub_matches.subcommand() {
+        ("inspect", Some(sub_matches)) => {
+            let stats = cache.stats();
+            println!(
+                "{} transactions, {} bytes",
+                stats.transactions, stats.transaction_bytes
+            );
+            println!(
+                "signature lists of {} addresses, {} bytes",
+                stats.addresses, stats.signature_bytes
+            );
+            for signature in sub_matches.values_of("signature").into_iter().flatten() {
+                let signature = Signature::from_str(signature).unwrap();
+                match cache.get_transaction(&signature) {
+                    Some(cached) => println!(
+                        "transaction {}: slot {}, fetched from {}",
+                        signature, cached.transaction.slot, cached.endpoint
+                    ),
+                    None => println!("transaction {}: not cached", signature),
+                }
+            }
+            for address in sub_matches.values_of("address").into_iter().flatten() {
+                let address = Pubkey::from_str(address).unwrap();
+                match cache.get_signatures(&address) {
+                    Some(cached) => println!(
+                        "signatures of {}: {}, up to slot {}, fetched from {}",
+                        address,
+                        cached.signatures.len(),
+                        cached
+                            .signatures
+                            .first()
+                            .map(|s| s.slot)
+                            .unwrap_or_default(),
+                        cached.endpoints.into_iter().collect::<Vec<_>>().join(" ")
+                    ),
+                    None => println!("signatures of {}: not cached", address),
+                }
+            }
+        }
+        ("prune", Some(sub_matches)) => {
+            let older_than = sub_matches
+                .value_of("older_than")
+                .map(|days| Duration::from_secs(days.parse::<u64>().unwrap() * 24 * 60 * 60));
+            let (transactions, addresses) = cache.prune(older_than);
+            println!(
+                "pruned {} transactions and the signature lists of {} addresses",
+                transactions, addresses
+            );
+        }
+        _ => unreachable!(),
     }
 }
 
@@ -167,6 +420,47 @@ fn main() {
                 .global(true)
                 .help("Do all processing without sending transactions"),
         )
+        .arg(
+            Arg::with_name("token_accounts_by_owner")
+                .long("token-accounts-by-owner")
+                .takes_value(false)
+                .global(true)
+                .help(
+                    "Find the owners' token accounts with getTokenAccountsByOwner instead of \
+                     getProgramAccounts, which is used by default and falls back to the former \
+                     if the RPC endpoint rejects it",
+                ),
+        )
+        .arg(
+            Arg::with_name("concurrency")
+                .long("concurrency")
+                .value_name("N")
+                .takes_value(true)
+                .global(true)
+                .validator(|value| match value.parse::<usize>() {
+                    Ok(concurrency) if concurrency > 0 => Ok(()),
+                    _ => Err(format!(
+                        "invalid concurrency {}, expected a positive integer",
+                        value
+                    )),
+                })
+                .help(
+                    "How many RPC requests the audit sends at once, at most. Lowered \
+                     automatically while the RPC endpoint answers with 429 Too Many Requests \
+                     [default: 8]",
+                ),
+        )
+        .arg(
+            Arg::with_name("cache_dir")
+                .long("cache-dir")
+                .value_name("DIR")
+                .takes_value(true)
+                .global(true)
+                .help(
+                    "Keep the fetched transactions and signature lists in this directory, so \
+                     that later audits only fetch what's new since",
+                ),
+        )
         .arg(
             Arg::with_name("skip_genesis_block_check")
                 .long("skip-genesis-block-check")
@@ -178,43 +472,220 @@ fn main() {
             Arg::with_name("json_rpc_url")
                 .short("u")
                 .long("url")
-                .value_name("URL_OR_MONIKER")
+                .value_name("[ROLE=]URL_OR_MONIKER")
                 .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
                 .global(true)
-                .validator(is_url_or_moniker)
+                .validator(is_endpoint_list)
                 .help(
                     "URL for Solana's JSON RPC or moniker (or their first letter): \
                        [mainnet-beta, testnet, devnet, localhost] \
-                    Default from the configuration file.",
+                    Default from the configuration file. \
+                    Repeat it, or separate them with commas, for several endpoints, tried in \
+                    order whenever one fails. Prefix one with accounts= to only send it \
+                    account queries, or with history= to only send it transaction history \
+                    queries, such as an archival node.",
                 ),
         )
         .subcommand(
             clap::SubCommand::with_name("audit")
                 .about("Audit all accounts for the owners on the given mints")
-                .arg(mint_address_arg())
-                .arg(owner_keypair_arg()),
+                .arg(mint_address_arg().conflicts_with("mint_wide"))
+                .arg(owner_keypair_arg().required_unless_one(&[
+                    "owners_file",
+                    "derivation_path_template",
+                    "mint_wide",
+                    "account",
+                ]))
+                .arg(owners_file_arg().conflicts_with("mint_wide"))
+                .arg(seed_phrase_file_arg())
+                .arg(derivation_path_template_arg().conflicts_with("mint_wide"))
+                .arg(derivation_range_arg())
+                .arg(allow_non_system_owners_arg())
+                .arg(
+                    Arg::with_name("mint_wide")
+                        .long("mint-wide")
+                        .takes_value(true)
+                        .value_name("MINT_ADDRESS")
+                        .validator(is_valid_pubkey)
+                        .conflicts_with_all(&["owner", "historical", "outbound"])
+                        .help("Audit every account of the mint, whoever owns it"),
+                )
+                .arg(
+                    Arg::with_name("account")
+                        .long("account")
+                        .takes_value(true)
+                        .value_name("ADDRESS")
+                        .multiple(true)
+                        .validator(is_valid_pubkey)
+                        .conflicts_with_all(&[
+                            "owner",
+                            "owners_file",
+                            "derivation_path_template",
+                            "mint",
+                            "mint_wide",
+                            "historical",
+                            "outbound",
+                        ])
+                        .help(
+                            "Audit just these token accounts, under their current owners, \
+                             such as an account from a customer ticket",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("historical")
+                        .long("historical")
+                        .takes_value(false)
+                        .help(
+                            "Also audit the token accounts found in the owners' transaction \
+                             history, including the ones reassigned away or closed since",
+                        ),
+                )
+                .arg(
+                    Arg::with_name("outbound")
+                        .long("outbound")
+                        .takes_value(false)
+                        .help(
+                            "Also report the token accounts the owners themselves reassigned to \
+                             someone else, as a compromised key would, and audit what happened \
+                             to them next",
+                        ),
+                ),
         )
         .subcommand(
             clap::SubCommand::with_name("cleanup")
                 .about("Revoke all account delegations for the owners on the given mints")
                 .arg(mint_address_arg())
-                .arg(owner_keypair_arg()),
+                .arg(
+                    owner_keypair_arg()
+                        .required_unless_one(&["owners_file", "derivation_path_template"]),
+                )
+                .arg(owners_file_arg())
+                .arg(seed_phrase_file_arg())
+                .arg(derivation_path_template_arg())
+                .arg(derivation_range_arg())
+                .arg(allow_non_system_owners_arg())
+                .arg(
+                    Arg::with_name("multisig_signer")
+                        .long("multisig-signer")
+                        .takes_value(true)
+                        .value_name("SIGNER_KEYPAIR")
+                        .multiple(true)
+                        .number_of_values(1)
+                        .validator(is_valid_signer)
+                        .requires("allow_non_system_owners")
+                        .help(
+                            "Keypair of a signer of the multisig owners, enough of which are \
+                             needed to revoke their delegations. Without them, an unsigned \
+                             plan is printed instead",
+                        ),
+                ),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("inventory")
+                .about(
+                    "List the token accounts, delegations and close authorities of the owners \
+                     on every mint, without fetching any transaction history",
+                )
+                .arg(mint_address_arg().help(
+                    "Address of an SPL token mint your organization supports, the accounts on \
+                     any other mint are flagged as unsupported",
+                ))
+                .arg(
+                    owner_keypair_arg()
+                        .required_unless_one(&["owners_file", "derivation_path_template"]),
+                )
+                .arg(owners_file_arg())
+                .arg(seed_phrase_file_arg())
+                .arg(derivation_path_template_arg())
+                .arg(derivation_range_arg())
+                .arg(allow_non_system_owners_arg()),
+        )
+        .subcommand(
+            clap::SubCommand::with_name("cache")
+                .about("Inspect or prune the cache given by --cache-dir")
+                .setting(clap::AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    clap::SubCommand::with_name("inspect")
+                        .about("Show what the cache holds")
+                        .arg(
+                            Arg::with_name("signature")
+                                .long("signature")
+                                .value_name("SIGNATURE")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .validator(|value| {
+                                    Signature::from_str(&value)
+                                        .map(|_| ())
+                                        .map_err(|err| format!("invalid signature: {}", err))
+                                })
+                                .help("Also show the cached transaction of this signature"),
+                        )
+                        .arg(
+                            Arg::with_name("address")
+                                .long("address")
+                                .value_name("ADDRESS")
+                                .takes_value(true)
+                                .multiple(true)
+                                .number_of_values(1)
+                                .validator(is_valid_pubkey)
+                                .help("Also show the cached signature list of this address"),
+                        ),
+                )
+                .subcommand(
+                    clap::SubCommand::with_name("prune")
+                        .about("Remove entries from the cache, all of them by default")
+                        .arg(
+                            Arg::with_name("older_than")
+                                .long("older-than")
+                                .value_name("DAYS")
+                                .takes_value(true)
+                                .validator(|value| {
+                                    value
+                                        .parse::<u64>()
+                                        .map(|_| ())
+                                        .map_err(|_| format!("invalid number of days {}", value))
+                                })
+                                .help("Only remove the entries cached more than DAYS days ago"),
+                        ),
+                ),
         )
         .get_matches();
 
+    let cache = matches.value_of("cache_dir").map(|cache_dir| {
+        Cache::open(cache_dir).unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            exit(1);
+        })
+    });
+    // doesn't need an RPC endpoint or a fee payer
+    if let ("cache", Some(sub_matches)) = matches.subcommand() {
+        let cache = cache.unwrap_or_else(|| {
+            eprintln!("error: the cache subcommand needs --cache-dir");
+            exit(1);
+        });
+        run_cache(&cache, sub_matches);
+        return;
+    }
+
     let mut wallet_manager = None;
     let dry_run = matches.is_present("dry_run");
-    let config = {
+    let mut config = {
         let cli_config = if let Some(config_file) = matches.value_of("config_file") {
             solana_cli_config::Config::load(config_file).unwrap_or_default()
         } else {
             solana_cli_config::Config::default()
         };
-        let json_rpc_url = normalize_to_url_if_moniker(
-            matches
-                .value_of("json_rpc_url")
-                .unwrap_or(&cli_config.json_rpc_url),
-        );
+        let endpoints = match matches.values_of("json_rpc_url") {
+            Some(endpoints) => Endpoints::parse(endpoints),
+            None => Endpoints::parse(vec![cli_config.json_rpc_url.as_str()]),
+        }
+        .unwrap_or_else(|e| {
+            eprintln!("error: {}", e);
+            exit(1);
+        });
 
         let fee_payer = signer_from_path(
             &matches,
@@ -229,37 +700,115 @@ fn main() {
             exit(1);
         });
 
-        let rpc_client =
-            RpcClient::new_with_commitment(json_rpc_url.clone(), CommitmentConfig::confirmed());
-
         inc_20210825::config::Config {
-            json_rpc_url,
-            rpc_client,
+            endpoints,
             fee_payer,
             dry_run,
             verbose: matches.is_present("verbose"),
+            token_accounts_by_owner: matches.is_present("token_accounts_by_owner"),
+            concurrency: matches
+                .value_of("concurrency")
+                .map(|concurrency| concurrency.parse().unwrap())
+                .unwrap_or(fetch::DEFAULT_CONCURRENCY),
+            cache,
         }
     };
 
     match matches.subcommand() {
         ("audit", Some(sub_matches)) => {
-            let (owners, mints) =
-                get_owners_and_mints(sub_matches, true, &config.rpc_client, &mut wallet_manager);
+            let scope = if let Some(mint) = sub_matches.value_of("mint_wide") {
+                let mint = get_signer(
+                    sub_matches,
+                    mint,
+                    &mut wallet_manager,
+                    /* allow_null_signer = */ true,
+                )
+                .pubkey();
+                check_mints(&config.endpoints, &[mint]);
+                audit::AuditScope::MintWide(mint)
+            } else if let Some(token_addresses) = sub_matches.values_of("account") {
+                let token_addresses = token_addresses
+                    .map(|token_address| {
+                        get_signer(
+                            sub_matches,
+                            token_address,
+                            &mut wallet_manager,
+                            /* allow_null_signer = */ true,
+                        )
+                        .pubkey()
+                    })
+                    .collect();
+                audit::AuditScope::Accounts(token_addresses)
+            } else {
+                let CheckedOwners {
+                    owners,
+                    mints,
+                    owner_metadata,
+                    ..
+                } = get_owners_and_mints(
+                    sub_matches,
+                    true,
+                    sub_matches.is_present("allow_non_system_owners"),
+                    &config.endpoints,
+                    &mut wallet_manager,
+                );
+                audit::AuditScope::Owners {
+                    owners,
+                    mints,
+                    historical: sub_matches.is_present("historical"),
+                    outbound: sub_matches.is_present("outbound"),
+                    owner_metadata,
+                }
+            };
             if !matches.is_present("skip_genesis_block_check") {
-                check_rpc_has_genesis_block(&config.rpc_client, &config.json_rpc_url);
+                check_rpc_has_genesis_block(&mut config.endpoints);
             }
 
-            audit::run(config, owners, mints);
+            audit::run(config, scope);
         }
         ("cleanup", Some(sub_matches)) => {
             let allow_null_signer = dry_run;
-            let (owners, mints) = get_owners_and_mints(
+            let CheckedOwners {
+                owners,
+                mints,
+                owner_metadata,
+                owner_kinds,
+            } = get_owners_and_mints(
                 sub_matches,
                 allow_null_signer,
-                &config.rpc_client,
+                sub_matches.is_present("allow_non_system_owners"),
+                &config.endpoints,
+                &mut wallet_manager,
+            );
+            let multisig_signers = sub_matches
+                .values_of("multisig_signer")
+                .into_iter()
+                .flatten()
+                .map(|p| get_signer(sub_matches, p, &mut wallet_manager, allow_null_signer))
+                .collect::<Vec<_>>();
+            cleanup::run(
+                config,
+                owners,
+                mints,
+                &owner_kinds,
+                &owner_metadata,
+                &multisig_signers,
+            );
+        }
+        ("inventory", Some(sub_matches)) => {
+            let CheckedOwners {
+                owners,
+                mints: supported_mints,
+                owner_metadata,
+                ..
+            } = get_owners_and_mints(
+                sub_matches,
+                true,
+                sub_matches.is_present("allow_non_system_owners"),
+                &config.endpoints,
                 &mut wallet_manager,
             );
-            cleanup::run(config, owners, mints);
+            inventory::run(config, owners, supported_mints, owner_metadata);
         }
         _ => unreachable!(),
     }
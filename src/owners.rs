@@ -0,0 +1,346 @@
+use {
+    crate::{endpoints::Endpoints, fetch},
+    serde::{Deserialize, Serialize},
+    solana_sdk::{program_pack::Pack, pubkey::Pubkey, system_program},
+    spl_token::state::Multisig,
+    std::collections::HashMap,
+};
+
+/// One owner from an owners file, with the values of the extra columns
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OwnerRow {
+    // address or keypair path, just like on the command line
+    pub owner: String,
+    pub values: Vec<String>,
+}
+
+/// Owners loaded from a CSV or JSON lines file
+///
+/// CSV files must start with a header row, and the first column holds the
+/// owners. JSON lines files hold one object per line with an `owner` key, and
+/// every other key becomes a column.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct OwnersFile {
+    // names of the extra columns, besides the owner
+    pub columns: Vec<String>,
+    pub rows: Vec<OwnerRow>,
+}
+
+impl OwnersFile {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("couldn't read owners file {}: {}", path, err))?;
+        if path.ends_with(".jsonl") || path.ends_with(".json") {
+            Self::parse_json_lines(&contents)
+        } else {
+            Self::parse_csv(&contents)
+        }
+        .map_err(|err| format!("invalid owners file {}: {}", path, err))
+    }
+
+    fn parse_csv(contents: &str) -> Result<Self, String> {
+        let mut lines = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty());
+        let header = lines.next().ok_or("missing header row")?;
+        let columns = split_csv_line(header)
+            .into_iter()
+            .skip(1)
+            .collect::<Vec<_>>();
+        let rows = lines
+            .enumerate()
+            .map(|(index, line)| {
+                let mut fields = split_csv_line(line).into_iter();
+                let owner = fields
+                    .next()
+                    .filter(|owner| !owner.is_empty())
+                    .ok_or_else(|| format!("missing owner on row {}", index + 1))?;
+                let mut values = fields.collect::<Vec<_>>();
+                values.resize(columns.len(), String::new());
+                Ok(OwnerRow { owner, values })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self { columns, rows })
+    }
+
+    fn parse_json_lines(contents: &str) -> Result<Self, String> {
+        let objects = contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .enumerate()
+            .map(|(index, line)| {
+                serde_json::from_str::<serde_json::Map<String, serde_json::Value>>(line)
+                    .map_err(|err| format!("line {}: {}", index + 1, err))
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+
+        // columns in the order they first show up, the keys of each line being sorted
+        let mut columns = Vec::<String>::new();
+        for object in &objects {
+            for key in object.keys() {
+                if key != "owner" && !columns.contains(key) {
+                    columns.push(key.clone());
+                }
+            }
+        }
+
+        let to_string = |value: &serde_json::Value| match value {
+            serde_json::Value::String(value) => value.clone(),
+            serde_json::Value::Null => String::new(),
+            value => value.to_string(),
+        };
+        let rows = objects
+            .iter()
+            .enumerate()
+            .map(|(index, object)| {
+                let owner = object
+                    .get("owner")
+                    .map(to_string)
+                    .filter(|owner| !owner.is_empty())
+                    .ok_or_else(|| format!("missing owner on line {}", index + 1))?;
+                let values = columns
+                    .iter()
+                    .map(|column| object.get(column).map(to_string).unwrap_or_default())
+                    .collect();
+                Ok(OwnerRow { owner, values })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        Ok(Self { columns, rows })
+    }
+}
+
+/// The value as a csv field, quoted if it holds commas or quotes
+pub fn csv_field(value: &str) -> String {
+    if value.contains(',') || value.contains('"') {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+// fields may be double quoted to hold commas, with quotes escaped by doubling them
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+            c => field.push(c),
+        }
+    }
+    fields.push(field.trim().to_string());
+    fields
+}
+
+/// Extra columns about each owner, carried into the reports
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct OwnerMetadata {
+    pub columns: Vec<String>,
+    pub values_by_owner: HashMap<Pubkey, Vec<String>>,
+}
+
+impl OwnerMetadata {
+    /// The values of the owner, or empty ones if it wasn't in the owners file
+    pub fn values(&self, owner: &Pubkey) -> Vec<String> {
+        self.values_by_owner
+            .get(owner)
+            .cloned()
+            .unwrap_or_else(|| vec![String::new(); self.columns.len()])
+    }
+
+    /// The values of the owner for log lines, like ` (customer_id: 42)`, or
+    /// nothing without any columns
+    pub fn describe(&self, owner: &Pubkey) -> String {
+        if self.columns.is_empty() {
+            return String::new();
+        }
+        let values = self.values(owner);
+        let description = self
+            .columns
+            .iter()
+            .zip(values.iter())
+            .map(|(column, value)| format!("{}: {}", column, value))
+            .collect::<Vec<_>>()
+            .join(", ");
+        format!(" ({})", description)
+    }
+
+    /// Values rendered as trailing csv columns, empty without any columns
+    pub fn format_values(values: &[String]) -> String {
+        values
+            .iter()
+            .map(|value| format!(",{}", csv_field(value)))
+            .collect()
+    }
+}
+
+/// What kind of authority an owner address is, which decides how its
+/// delegations can be revoked
+#[derive(Clone, Debug, PartialEq)]
+pub enum OwnerKind {
+    /// A wallet, signing for itself
+    Wallet,
+    /// An SPL token multisig, needing `m` of its signers
+    Multisig(Box<Multisig>),
+    /// A program-derived address, or an account of another program, which
+    /// only that program can sign for
+    Program(Option<Pubkey>),
+}
+
+impl OwnerKind {
+    /// The kinds of the owners, fetched in as few requests as possible
+    pub fn fetch_all(
+        endpoints: &Endpoints,
+        owners: &[Pubkey],
+    ) -> Result<Vec<Result<Self, String>>, String> {
+        let owner_accounts = fetch::get_multiple_accounts(endpoints, owners)
+            .map_err(|err| format!("couldn't fetch the owners: {}", err))?;
+        Ok(owners
+            .iter()
+            .zip(owner_accounts)
+            .map(|(owner, owner_account)| match owner_account {
+                Some(owner_account) => Self::from_account(owner, &owner_account),
+                // A non-existent owner just means a system account with no lamports,
+                // which is a valid sitation for an owner account.
+                None if !owner.is_on_curve() => Ok(Self::Program(None)),
+                None => Ok(Self::Wallet),
+            })
+            .collect())
+    }
+
+    fn from_account(
+        owner: &Pubkey,
+        owner_account: &solana_sdk::account::Account,
+    ) -> Result<Self, String> {
+        if owner_account.owner == system_program::id() {
+            Ok(Self::Wallet)
+        } else if owner_account.owner == spl_token::id() {
+            if owner_account.data.len() == Multisig::LEN {
+                if let Ok(multisig) = Multisig::unpack(&owner_account.data) {
+                    return Ok(Self::Multisig(Box::new(multisig)));
+                }
+            }
+            Err(format!("Account {} is not owned by the system program, actually owned by the SPL token program. Maybe this is a mint?", owner))
+        } else {
+            Ok(Self::Program(Some(owner_account.owner)))
+        }
+    }
+
+    /// Why the owner isn't a wallet, for error messages and cleanup plans
+    pub fn describe(&self, owner: &Pubkey) -> String {
+        match self {
+            Self::Wallet => format!("{} is a wallet", owner),
+            Self::Multisig(multisig) => format!(
+                "{} is an SPL token multisig needing {} of {} signers",
+                owner, multisig.m, multisig.n
+            ),
+            Self::Program(Some(program_id)) => {
+                format!("{} is owned by program {}", owner, program_id)
+            }
+            Self::Program(None) => format!("{} is a program-derived address", owner),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_csv_owners() {
+        let owners_file = OwnersFile::parse_csv(
+            "owner,customer_id,label\n\
+             EriSViggFFQ72fYgCKYyattiY3rDsx9bnMgMUpGa5x2H,42,\"hot, wallet\"\n\
+             \n\
+             /path/to/keypair.json\n",
+        )
+        .unwrap();
+        assert_eq!(owners_file.columns, vec!["customer_id", "label"]);
+        assert_eq!(
+            owners_file.rows,
+            vec![
+                OwnerRow {
+                    owner: "EriSViggFFQ72fYgCKYyattiY3rDsx9bnMgMUpGa5x2H".to_string(),
+                    values: vec!["42".to_string(), "hot, wallet".to_string()],
+                },
+                OwnerRow {
+                    owner: "/path/to/keypair.json".to_string(),
+                    values: vec![String::new(), String::new()],
+                },
+            ]
+        );
+        assert!(OwnersFile::parse_csv("").is_err());
+        assert!(OwnersFile::parse_csv("owner,label\n,cold").is_err());
+    }
+
+    #[test]
+    fn parse_json_lines_owners() {
+        let owners_file = OwnersFile::parse_json_lines(
+            "{\"owner\": \"usb://ledger\", \"customer_id\": 42}\n\
+             {\"owner\": \"/path/to/keypair.json\", \"label\": \"cold\"}\n",
+        )
+        .unwrap();
+        assert_eq!(owners_file.columns, vec!["customer_id", "label"]);
+        assert_eq!(owners_file.rows[0].values, vec!["42", ""]);
+        assert_eq!(owners_file.rows[1].values, vec!["", "cold"]);
+        assert!(OwnersFile::parse_json_lines("{\"label\": \"cold\"}").is_err());
+    }
+
+    #[test]
+    fn owner_kinds() {
+        let owner = Pubkey::new_unique();
+        let account = |program_id, data| solana_sdk::account::Account {
+            lamports: 1,
+            data,
+            owner: program_id,
+            executable: false,
+            rent_epoch: 0,
+        };
+        assert_eq!(
+            OwnerKind::from_account(&owner, &account(system_program::id(), vec![])),
+            Ok(OwnerKind::Wallet)
+        );
+        let program_id = Pubkey::new_unique();
+        assert_eq!(
+            OwnerKind::from_account(&owner, &account(program_id, vec![1, 2, 3])),
+            Ok(OwnerKind::Program(Some(program_id)))
+        );
+
+        let multisig = Multisig {
+            m: 2,
+            n: 3,
+            is_initialized: true,
+            signers: [Pubkey::new_unique(); spl_token::instruction::MAX_SIGNERS],
+        };
+        let mut data = vec![0; Multisig::LEN];
+        Multisig::pack(multisig, &mut data).unwrap();
+        assert_eq!(
+            OwnerKind::from_account(&owner, &account(spl_token::id(), data)),
+            Ok(OwnerKind::Multisig(Box::new(multisig)))
+        );
+        // a mint given by mistake
+        assert!(OwnerKind::from_account(
+            &owner,
+            &account(spl_token::id(), vec![0; spl_token::state::Mint::LEN])
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn format_owner_metadata() {
+        assert_eq!(OwnerMetadata::format_values(&[]), "");
+        assert_eq!(
+            OwnerMetadata::format_values(&["42".to_string(), "hot, \"new\"".to_string()]),
+            ",42,\"hot, \"\"new\"\"\""
+        );
+    }
+}
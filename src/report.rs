@@ -1,113 +1,627 @@
 use {
-    crate::token::TokenAccountEntry,
+    crate::{
+        owners::{csv_field, OwnerMetadata},
+        token::{
+            owner_period_index, IncompleteAccount, IncompleteOwner, OutboundReassignment,
+            TokenAccountEntry, TokenEvent,
+        },
+    },
     serde::{Deserialize, Serialize},
+    solana_account_decoder::parse_token::token_amount_to_ui_amount,
     solana_sdk::pubkey::Pubkey,
-    std::{collections::HashMap, io::Write},
+    std::{
+        collections::{BTreeMap, HashMap},
+        io::Write,
+    },
 };
 
+fn format_amount(amount: u64, decimals: u8) -> String {
+    token_amount_to_ui_amount(amount, decimals).ui_amount_string
+}
+
+fn format_balance_change(balance_change: Option<i128>, decimals: u8) -> String {
+    match balance_change {
+        Some(balance_change) => format!(
+            "{}{}",
+            if balance_change < 0 { "-" } else { "" },
+            format_amount(balance_change.unsigned_abs() as u64, decimals)
+        ),
+        None => String::new(),
+    }
+}
+
+// extra columns about the owner from the owners file, if any
+fn format_owner_metadata(account_entry: &TokenAccountEntry) -> String {
+    OwnerMetadata::format_values(&account_entry.owner_metadata)
+}
+
+// empty for top-level instructions
+fn format_outer_program(outer_program_id: Option<Pubkey>) -> String {
+    outer_program_id
+        .map(|outer_program_id| outer_program_id.to_string())
+        .unwrap_or_default()
+}
+
+// accounts found in the owner's history are flagged, since they're not its own anymore
+fn discovery(account_entry: &TokenAccountEntry) -> &'static str {
+    if account_entry.historical {
+        "historical"
+    } else {
+        "current"
+    }
+}
+
+// auxiliary accounts were the vector of the attack, even with a clean history
+fn account_type(account_entry: &TokenAccountEntry) -> &'static str {
+    if account_entry.associated {
+        "associated"
+    } else {
+        "auxiliary"
+    }
+}
+
+fn join_pubkeys(pubkeys: &[Pubkey]) -> String {
+    pubkeys
+        .iter()
+        .map(|pubkey| pubkey.to_string())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct Report {
     pub entries_by_token_address: HashMap<Pubkey, TokenAccountEntry>,
+    // names of the extra columns from the owners file, appended to every account row
+    pub owner_metadata_columns: Vec<String>,
+    // accounts the audited owners handed to someone else, oldest first
+    pub outbound_reassignments: Vec<OutboundReassignment>,
+    // owners whose accounts or history couldn't be fetched, so some of their accounts may be
+    // missing from the report
+    pub incomplete_owners: Vec<IncompleteOwner>,
+    // accounts given to audit which couldn't be fetched at all
+    pub incomplete_accounts: Vec<IncompleteAccount>,
 }
 
 impl Report {
     pub fn new() -> Self {
         Self {
             entries_by_token_address: HashMap::new(),
+            owner_metadata_columns: vec![],
+            outbound_reassignments: vec![],
+            incomplete_owners: vec![],
+            incomplete_accounts: vec![],
+        }
+    }
+
+    fn format_owner_metadata_columns(&self) -> String {
+        OwnerMetadata::format_values(&self.owner_metadata_columns)
+    }
+
+    // the extra columns left empty, for rows without a known owner
+    fn format_no_owner_metadata(&self) -> String {
+        ",".repeat(self.owner_metadata_columns.len())
+    }
+
+    // the decimals of each mint, taken from any of its accounts, since those whose mint couldn't
+    // be fetched default to 0
+    fn mint_decimals(&self) -> HashMap<Pubkey, u8> {
+        let mut mint_decimals = HashMap::<Pubkey, u8>::new();
+        for account_entry in self.entries_by_token_address.values() {
+            let decimals = mint_decimals.entry(account_entry.mint).or_default();
+            *decimals = (*decimals).max(account_entry.decimals);
+        }
+        mint_decimals
+    }
+
+    /// Output the tokens taken by inherited delegations as csv, totalled per
+    /// account, per owner and per mint
+    ///
+    /// The amounts are the balance decreases of the audited accounts, taken
+    /// from the token balances in the transaction status meta. Transactions
+    /// whose meta has no token balances can't be quantified and are listed
+    /// separately, so the totals are only authoritative if there are none.
+    pub fn losses<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        let mut losses_by_owner = BTreeMap::<(Pubkey, Pubkey), (u64, u8, usize)>::new();
+        let mut losses_by_mint = BTreeMap::<Pubkey, (u64, u8, usize)>::new();
+        let mint_decimals = self.mint_decimals();
+
+        writeln!(&mut writer, "Loss Per Account")?;
+        writeln!(
+            &mut writer,
+            "Account Address,Owner Address,Mint,Loss,Fraudulent Signatures,Unquantified Signatures{}",
+            self.format_owner_metadata_columns()
+        )?;
+        for (account_address, account_entry) in &self.entries_by_token_address {
+            let loss = account_entry.loss();
+            if loss.transaction_ids.is_empty() {
+                continue;
+            }
+            let decimals = mint_decimals[&account_entry.mint];
+            writeln!(
+                &mut writer,
+                "{},{},{},{},{},{}{}",
+                account_address,
+                account_entry.current_owner,
+                account_entry.mint,
+                format_amount(loss.amount, decimals),
+                loss.transaction_ids
+                    .iter()
+                    .map(|transaction_id| transaction_id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                loss.unquantified_transaction_ids
+                    .iter()
+                    .map(|transaction_id| transaction_id.to_string())
+                    .collect::<Vec<_>>()
+                    .join(" "),
+                format_owner_metadata(account_entry)
+            )?;
+            for total in [
+                losses_by_owner
+                    .entry((account_entry.current_owner, account_entry.mint))
+                    .or_default(),
+                losses_by_mint.entry(account_entry.mint).or_default(),
+            ] {
+                total.0 = total.0.saturating_add(loss.amount);
+                total.1 = decimals;
+                total.2 += 1;
+            }
+        }
+
+        writeln!(&mut writer)?;
+        writeln!(&mut writer, "Loss Per Owner")?;
+        writeln!(&mut writer, "Owner Address,Mint,Loss,Accounts")?;
+        for ((owner, mint), (amount, decimals, accounts)) in &losses_by_owner {
+            writeln!(
+                &mut writer,
+                "{},{},{},{}",
+                owner,
+                mint,
+                format_amount(*amount, *decimals),
+                accounts
+            )?;
+        }
+
+        writeln!(&mut writer)?;
+        writeln!(&mut writer, "Loss Per Mint")?;
+        writeln!(&mut writer, "Mint,Loss,Accounts")?;
+        for (mint, (amount, decimals, accounts)) in &losses_by_mint {
+            writeln!(
+                &mut writer,
+                "{},{},{}",
+                mint,
+                format_amount(*amount, *decimals),
+                accounts
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Output how exposed each mint is across all of its audited accounts as
+    /// csv, for token issuers
+    ///
+    /// Accounts at risk still have a live delegation from a previous owner,
+    /// and the amount at risk is what those delegations can still take.
+    pub fn exposure<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        #[derive(Default)]
+        struct Exposure {
+            decimals: u8,
+            accounts: usize,
+            reassigned_accounts: usize,
+            fraud_accounts: usize,
+            loss: u64,
+            accounts_at_risk: usize,
+            amount_at_risk: u64,
+            foreign_close_authority_accounts: usize,
+            unrecognized_accounts: usize,
+        }
+
+        let mint_decimals = self.mint_decimals();
+        let mut exposures = BTreeMap::<Pubkey, Exposure>::new();
+        for account_entry in self.entries_by_token_address.values() {
+            let exposure = exposures.entry(account_entry.mint).or_default();
+            exposure.decimals = mint_decimals[&account_entry.mint];
+            exposure.accounts += 1;
+            if !account_entry.owner_changes.is_empty() {
+                exposure.reassigned_accounts += 1;
+            }
+            let loss = account_entry.loss();
+            if !loss.transaction_ids.is_empty() {
+                exposure.fraud_accounts += 1;
+                exposure.loss = exposure.loss.saturating_add(loss.amount);
+            }
+            let amount_at_risk = account_entry.amount_at_risk();
+            if amount_at_risk > 0 {
+                exposure.accounts_at_risk += 1;
+                exposure.amount_at_risk = exposure.amount_at_risk.saturating_add(amount_at_risk);
+            }
+            if account_entry.foreign_close_authority().is_some() {
+                exposure.foreign_close_authority_accounts += 1;
+            }
+            if !account_entry.unknown_instructions.is_empty() {
+                exposure.unrecognized_accounts += 1;
+            }
+        }
+
+        writeln!(&mut writer, "Issuer Exposure Report")?;
+        writeln!(
+            &mut writer,
+            "Mint,Accounts,Reassigned Accounts,Accounts With Possible Fraud,Loss,Accounts At Risk,Amount At Risk,Accounts With Foreign Close Authority,Accounts With Unrecognized Instructions"
+        )?;
+        for (mint, exposure) in &exposures {
+            writeln!(
+                &mut writer,
+                "{},{},{},{},{},{},{},{},{}",
+                mint,
+                exposure.accounts,
+                exposure.reassigned_accounts,
+                exposure.fraud_accounts,
+                format_amount(exposure.loss, exposure.decimals),
+                exposure.accounts_at_risk,
+                format_amount(exposure.amount_at_risk, exposure.decimals),
+                exposure.foreign_close_authority_accounts,
+                exposure.unrecognized_accounts,
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Output the accounts the audited owners handed to someone else as csv,
+    /// along with what happened to them next
+    ///
+    /// An owner reassigning its accounts away without anyone knowing is the
+    /// sign of a compromised key. The later owners are the ones the account
+    /// went through after the new owner, and the status tells whether it's
+    /// still held by the new owner, was reassigned again, came back or was
+    /// closed since.
+    pub fn outbound<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        writeln!(&mut writer, "Outbound Reassignments")?;
+        writeln!(
+            &mut writer,
+            "Status,Account Address,Mint,Previous Owner,New Owner,Set Owner Signature,Slot,Later Owners,Current Owner,Current Balance{}",
+            self.format_owner_metadata_columns()
+        )?;
+        for reassignment in &self.outbound_reassignments {
+            let account_entry = match self
+                .entries_by_token_address
+                .get(&reassignment.token_address)
+            {
+                Some(account_entry) => account_entry,
+                None => continue,
+            };
+            let owner_periods = account_entry.owner_periods();
+            let later_owners =
+                owner_periods
+                    .iter()
+                    .position(|owner_period| {
+                        owner_period.start.as_ref().map(|owner_change| {
+                            (owner_change.transaction_id, owner_change.new_owner)
+                        }) == Some((reassignment.transaction_id, reassignment.new_owner))
+                    })
+                    .map(|period_index| {
+                        owner_periods[period_index + 1..]
+                            .iter()
+                            .map(|owner_period| owner_period.owner)
+                            .collect::<Vec<_>>()
+                    })
+                    .unwrap_or_default();
+            let status = if account_entry.closed {
+                "closed"
+            } else if account_entry.current_owner == reassignment.previous_owner {
+                "returned"
+            } else if account_entry.current_owner == reassignment.new_owner {
+                "held by new owner"
+            } else {
+                "reassigned again"
+            };
+            let (current_owner, current_balance) = if account_entry.closed {
+                (String::new(), String::new())
+            } else {
+                (
+                    account_entry.current_owner.to_string(),
+                    format_amount(account_entry.current_amount, account_entry.decimals),
+                )
+            };
+            writeln!(
+                &mut writer,
+                "{},{},{},{},{},{},{},{},{},{}{}",
+                status,
+                reassignment.token_address,
+                account_entry.mint,
+                reassignment.previous_owner,
+                reassignment.new_owner,
+                reassignment.transaction_id,
+                reassignment.slot,
+                join_pubkeys(&later_owners),
+                current_owner,
+                current_balance,
+                OwnerMetadata::format_values(&reassignment.owner_metadata)
+            )?;
         }
+        Ok(())
     }
 
     /// Output the report as csv for easy reading
     ///
-    /// There are three different types of transactions to report:
+    /// There are four different types of transactions to report:
     ///   * possibly fraudulent transfers and burns
     ///   * owner assignment
     ///   * approvals
+    ///   * close authority assignment
     ///
     /// Transfers and burns will only be reported if they are signed by a key
     /// that is not the current owner, and there was an owner assignment at
     /// some point. These transactions must be investigated further to discover
     /// any loss of funds.
     ///
+    /// Each owner assignment starts a new ownership period, and each
+    /// delegation which survived into a period is reported along with what it
+    /// was used for during that period only.
+    ///
+    /// A close authority will be reported if it was assigned by a key that is
+    /// not the current owner, since it can close the account once drained and
+    /// take the rent lamports.
+    ///
+    /// Accounts which aren't the associated token account of their owner and
+    /// mint are flagged even without anything else to report, since the
+    /// attackers created such auxiliary accounts and reassigned them to their
+    /// victims.
+    ///
+    /// Close authority changes and unrecognized instructions are flagged as
+    /// well, but their transactions are only listed in the detailed report, so
+    /// that the fraud signatures column holds nothing but transfers and burns.
+    ///
     /// This way, it's easy to search each of these transactions on the explorer
     /// or other tools to see the chain of malicious transactions as needed.
     pub fn summary<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
         writeln!(&mut writer, "Summary Reassigned Token Account Report")?;
-        writeln!(&mut writer, "Status,Account Address,Owner Address,Set Owner Signature,Delegation Signature,Possibly Fraudulent Transfer and Burn Signatures")?;
+        writeln!(
+            &mut writer,
+            "Status,Account Address,Owner Address,Period Owner,Set Owner Signature,Delegation Signature,Possibly Fraudulent Transfer and Burn Signatures,Discovery,Account Type{}",
+            self.format_owner_metadata_columns()
+        )?;
         for (account_address, account_entry) in &self.entries_by_token_address {
-            if account_entry.owner_changes.is_empty() {
-                // no owner changes ever, we're safe!
-                writeln!(
-                    &mut writer,
-                    "Safe,{},{},,,",
-                    account_address, account_entry.current_owner
-                )?;
+            let owner_periods = account_entry.owner_periods();
+            if owner_periods.len() == 1 {
+                // no owner changes ever, we're safe! Unless it's an auxiliary account, which
+                // is worth a look anyway. Or unless the audit didn't get through the whole
+                // history, which says nothing about the rest of it
+                if account_entry.incomplete.is_none() {
+                    writeln!(
+                        &mut writer,
+                        "{},{},{},{},,,,{},{}{}",
+                        if account_entry.associated {
+                            "Safe"
+                        } else {
+                            "Notice - auxiliary account"
+                        },
+                        account_address,
+                        account_entry.current_owner,
+                        account_entry.current_owner,
+                        discovery(account_entry),
+                        account_type(account_entry),
+                        format_owner_metadata(account_entry)
+                    )?;
+                }
             } else {
-                // Make a separate entry for each owner change
-                // Note: this may show false positives if there are multiple
-                // owner changes
-                for owner_change in &account_entry.owner_changes {
-                    // check for any delegations *before* the owner change
+                // Make a separate entry for each ownership period following an
+                // owner change
+                let delegations = account_entry.delegation_timeline();
+                let inherited_delegations =
+                    account_entry.inherited_delegations(&owner_periods, &delegations);
+                for (period_index, owner_period) in owner_periods.iter().enumerate() {
+                    let owner_change = match &owner_period.start {
+                        Some(owner_change) => owner_change,
+                        None => continue,
+                    };
+                    let is_current_period = period_index == owner_periods.len() - 1;
+
+                    // check for any delegations which survived into this period
                     let mut line_has_been_printed = false;
-                    for delegate_change in &account_entry.delegate_changes {
-                        // uh oh, the delegate was changed before the owner change
-                        if delegate_change.slot <= owner_change.slot {
-                            let delegate = delegate_change.new_delegate;
-
-                            // let's find all transfers and burns by this key, that's most likely fraud
-                            let mut fraudulent_transactions = vec![];
-                            for burn in &account_entry.possible_delegate_burns {
-                                if burn.slot >= owner_change.slot && burn.signer == delegate {
-                                    fraudulent_transactions
-                                        .push(format!("{}", burn.transaction_id));
-                                }
-                            }
-                            for transfer in &account_entry.possible_delegate_transfers {
-                                if transfer.slot >= owner_change.slot && transfer.signer == delegate
-                                {
-                                    fraudulent_transactions
-                                        .push(format!("{}", transfer.transaction_id));
-                                }
-                            }
-
-                            if fraudulent_transactions.is_empty() {
-                                // no fraud yet, but *must* clear delegation if present
-                                writeln!(
-                                    &mut writer,
-                                    "Warning - clear delegation immediately,{},{},{},{},",
-                                    account_address,
-                                    account_entry.current_owner,
-                                    owner_change.transaction_id,
-                                    delegate_change.transaction_id
-                                )?;
-                            } else {
-                                // oh no, some fraud most likely
-                                writeln!(
-                                    &mut writer,
-                                    "Danger - possible fraud,{},{},{},{},{}",
-                                    account_address,
-                                    account_entry.current_owner,
-                                    owner_change.transaction_id,
-                                    delegate_change.transaction_id,
-                                    fraudulent_transactions.join(" ")
-                                )?;
-                            }
-                            line_has_been_printed = true;
+                    for inherited_delegation in inherited_delegations
+                        .iter()
+                        .filter(|inherited| inherited.period_index == period_index)
+                    {
+                        let delegation = inherited_delegation.delegation;
+                        let delegate_change = &delegation.approval;
+
+                        // all transfers and burns by this delegation during the period are most
+                        // likely fraud
+                        let fraudulent_transactions = inherited_delegation
+                            .used_transaction_ids
+                            .iter()
+                            .map(|transaction_id| format!("{}", transaction_id))
+                            .collect::<Vec<_>>();
+
+                        if !fraudulent_transactions.is_empty() {
+                            // oh no, some fraud most likely
+                            writeln!(
+                                &mut writer,
+                                "Danger - possible fraud,{},{},{},{},{},{},{},{}{}",
+                                account_address,
+                                account_entry.current_owner,
+                                owner_period.owner,
+                                owner_change.transaction_id,
+                                delegate_change.transaction_id,
+                                fraudulent_transactions.join(" "),
+                                discovery(account_entry),
+                                account_type(account_entry),
+                                format_owner_metadata(account_entry)
+                            )?;
+                        } else if is_current_period
+                            && delegation.is_live(account_entry.current_delegate)
+                        {
+                            // no fraud yet, but *must* clear delegation
+                            writeln!(
+                                &mut writer,
+                                "Warning - clear delegation immediately,{},{},{},{},{},,{},{}{}",
+                                account_address,
+                                account_entry.current_owner,
+                                owner_period.owner,
+                                owner_change.transaction_id,
+                                delegate_change.transaction_id,
+                                discovery(account_entry),
+                                account_type(account_entry),
+                                format_owner_metadata(account_entry)
+                            )?;
+                        } else {
+                            // the delegation was revoked, replaced or reset since, or the
+                            // period ended without it being used
+                            writeln!(
+                                &mut writer,
+                                "Safe - delegation no longer active,{},{},{},{},{},,{},{}{}",
+                                account_address,
+                                account_entry.current_owner,
+                                owner_period.owner,
+                                owner_change.transaction_id,
+                                delegate_change.transaction_id,
+                                discovery(account_entry),
+                                account_type(account_entry),
+                                format_owner_metadata(account_entry)
+                            )?;
                         }
+                        line_has_been_printed = true;
                     }
 
                     if !line_has_been_printed {
-                        // a reassignment was done, but no delegations, should be fine!
+                        // a reassignment was done, but no delegations, should be fine! Unless
+                        // it's an auxiliary account, just like the attackers handed out
                         writeln!(
                             &mut writer,
-                            "Safe - reassignment only,{},{},{},,",
+                            "{},{},{},{},{},,,{},{}{}",
+                            if account_entry.associated {
+                                "Safe - reassignment only"
+                            } else {
+                                "Warning - reassigned auxiliary account"
+                            },
                             account_address,
                             account_entry.current_owner,
-                            owner_change.transaction_id
+                            owner_period.owner,
+                            owner_change.transaction_id,
+                            discovery(account_entry),
+                            account_type(account_entry),
+                            format_owner_metadata(account_entry)
                         )?;
                     }
                 }
             }
+
+            if let Some(close_authority_change) = account_entry.latest_close_authority_change() {
+                if !account_entry.is_current_owner(&close_authority_change.signer) {
+                    match close_authority_change.new_close_authority {
+                        Some(close_authority)
+                            if !account_entry.is_current_owner(&close_authority) =>
+                        {
+                            // a foreign key can close the account and take its lamports
+                            writeln!(
+                                &mut writer,
+                                "Warning - reset close authority immediately,{},{},,,,,{},{}{}",
+                                account_address,
+                                account_entry.current_owner,
+                                discovery(account_entry),
+                                account_type(account_entry),
+                                format_owner_metadata(account_entry)
+                            )?;
+                        }
+                        _ => {
+                            // set by someone else, but to the current owner or cleared
+                            writeln!(
+                                &mut writer,
+                                "Safe - close authority reassignment only,{},{},,,,,{},{}{}",
+                                account_address,
+                                account_entry.current_owner,
+                                discovery(account_entry),
+                                account_type(account_entry),
+                                format_owner_metadata(account_entry)
+                            )?;
+                        }
+                    }
+                }
+            }
+
+            if !account_entry.unknown_instructions.is_empty() {
+                // the audit couldn't classify everything, so the above may be incomplete. The
+                // transactions are listed in the detailed report, since they're no sign of fraud
+                writeln!(
+                    &mut writer,
+                    "Warning - unrecognized instructions,{},{},,,,,{},{}{}",
+                    account_address,
+                    account_entry.current_owner,
+                    discovery(account_entry),
+                    account_type(account_entry),
+                    format_owner_metadata(account_entry)
+                )?;
+            }
+
+            if account_entry.incomplete.is_some() {
+                // part of the history couldn't be fetched, the errors are listed separately
+                writeln!(
+                    &mut writer,
+                    "Incomplete - history not fully fetched,{},{},,,,,{},{}{}",
+                    account_address,
+                    account_entry.current_owner,
+                    discovery(account_entry),
+                    account_type(account_entry),
+                    format_owner_metadata(account_entry)
+                )?;
+            }
+        }
+        for incomplete_account in &self.incomplete_accounts {
+            // nothing is known about it, the error is listed separately
+            writeln!(
+                &mut writer,
+                "Incomplete - account not fetched,{},,,,,,,{}",
+                incomplete_account.token_address,
+                self.format_no_owner_metadata()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Output what couldn't be fetched even after retries as csv, per account
+    /// and per owner
+    ///
+    /// The findings of an incomplete account only cover the part of its
+    /// history which was fetched. An incomplete owner may have accounts which
+    /// are missing from the report altogether, so the audit is worth running
+    /// again for it.
+    pub fn incomplete<W: Write>(&self, mut writer: W) -> std::io::Result<()> {
+        writeln!(&mut writer, "Incomplete Audits")?;
+        writeln!(
+            &mut writer,
+            "Account Address,Owner Address,Mint,Error{}",
+            self.format_owner_metadata_columns()
+        )?;
+        for (account_address, account_entry) in &self.entries_by_token_address {
+            if let Some(error) = &account_entry.incomplete {
+                writeln!(
+                    &mut writer,
+                    "{},{},{},{}{}",
+                    account_address,
+                    account_entry.current_owner,
+                    account_entry.mint,
+                    csv_field(error),
+                    format_owner_metadata(account_entry)
+                )?;
+            }
+        }
+        for incomplete_owner in &self.incomplete_owners {
+            writeln!(
+                &mut writer,
+                ",{},,{}{}",
+                incomplete_owner.owner,
+                csv_field(&incomplete_owner.error),
+                OwnerMetadata::format_values(&incomplete_owner.owner_metadata)
+            )?;
+        }
+        for incomplete_account in &self.incomplete_accounts {
+            writeln!(
+                &mut writer,
+                "{},,,{}{}",
+                incomplete_account.token_address,
+                csv_field(&incomplete_account.error),
+                self.format_no_owner_metadata()
+            )?;
         }
         Ok(())
     }
@@ -118,20 +632,70 @@ impl Report {
         writeln!(&mut writer, "Owner Reassignment Transactions")?;
         writeln!(
             &mut writer,
-            "Account Address,Owner,Signature,Slot,Previous Owner,New Owner,JSON Instruction"
+            "Account Address,Owner,Signature,Slot,Previous Owner,New Owner,Outer Instruction,Outer Program,JSON Instruction{}",
+            self.format_owner_metadata_columns()
         )?;
         for (account_address, account_entry) in &self.entries_by_token_address {
             for owner_change in &account_entry.owner_changes {
                 writeln!(
                     &mut writer,
-                    "{},{},{},{},{},{},{}",
+                    "{},{},{},{},{},{},{},{},{}{}",
                     account_address,
                     account_entry.current_owner,
                     owner_change.transaction_id,
                     owner_change.slot,
                     owner_change.signer,
                     owner_change.new_owner,
-                    owner_change.original_ix
+                    owner_change.outer_instruction_index,
+                    format_outer_program(owner_change.outer_program_id),
+                    owner_change.original_ix,
+                    format_owner_metadata(account_entry)
+                )?;
+            }
+        }
+        writeln!(&mut writer)?;
+        writeln!(&mut writer, "Ownership Periods")?;
+        writeln!(
+            &mut writer,
+            "Account Address,Owner,Period,Period Owner,Start Signature,Start Slot,Approvals,Transfers,Burns{}",
+            self.format_owner_metadata_columns()
+        )?;
+        for (account_address, account_entry) in &self.entries_by_token_address {
+            let owner_periods = account_entry.owner_periods();
+            let mut approvals = vec![0; owner_periods.len()];
+            let mut transfers = vec![0; owner_periods.len()];
+            let mut burns = vec![0; owner_periods.len()];
+            for delegate_change in &account_entry.delegate_changes {
+                approvals[owner_period_index(&owner_periods, delegate_change.order())] += 1;
+            }
+            for transfer in &account_entry.possible_delegate_transfers {
+                transfers[owner_period_index(&owner_periods, transfer.order())] += 1;
+            }
+            for burn in &account_entry.possible_delegate_burns {
+                burns[owner_period_index(&owner_periods, burn.order())] += 1;
+            }
+            for (period_index, owner_period) in owner_periods.iter().enumerate() {
+                writeln!(
+                    &mut writer,
+                    "{},{},{},{},{},{},{},{},{}{}",
+                    account_address,
+                    account_entry.current_owner,
+                    period_index,
+                    owner_period.owner,
+                    owner_period
+                        .start
+                        .as_ref()
+                        .map(|owner_change| owner_change.transaction_id.to_string())
+                        .unwrap_or_default(),
+                    owner_period
+                        .start
+                        .as_ref()
+                        .map(|owner_change| owner_change.slot.to_string())
+                        .unwrap_or_default(),
+                    approvals[period_index],
+                    transfers[period_index],
+                    burns[period_index],
+                    format_owner_metadata(account_entry)
                 )?;
             }
         }
@@ -139,20 +703,110 @@ impl Report {
         writeln!(&mut writer, "Delegation Change Transactions")?;
         writeln!(
             &mut writer,
-            "Account Address,Owner,Signature,Slot,Owner,Delegate,JSON Instruction"
+            "Account Address,Owner,Signature,Slot,Owner,Delegate,Outer Instruction,Outer Program,JSON Instruction{}",
+            self.format_owner_metadata_columns()
         )?;
         for (account_address, account_entry) in &self.entries_by_token_address {
             for delegate_change in &account_entry.delegate_changes {
                 writeln!(
                     &mut writer,
-                    "{},{},{},{},{},{},{}",
+                    "{},{},{},{},{},{},{},{},{}{}",
                     account_address,
                     account_entry.current_owner,
                     delegate_change.transaction_id,
                     delegate_change.slot,
                     delegate_change.signer,
                     delegate_change.new_delegate,
-                    delegate_change.original_ix
+                    delegate_change.outer_instruction_index,
+                    format_outer_program(delegate_change.outer_program_id),
+                    delegate_change.original_ix,
+                    format_owner_metadata(account_entry)
+                )?;
+            }
+        }
+        writeln!(&mut writer)?;
+        writeln!(&mut writer, "Delegation Revoke Transactions")?;
+        writeln!(
+            &mut writer,
+            "Account Address,Owner,Signature,Slot,Signer,Outer Instruction,Outer Program,JSON Instruction{}",
+            self.format_owner_metadata_columns()
+        )?;
+        for (account_address, account_entry) in &self.entries_by_token_address {
+            for delegate_revoke in &account_entry.delegate_revokes {
+                writeln!(
+                    &mut writer,
+                    "{},{},{},{},{},{},{},{}{}",
+                    account_address,
+                    account_entry.current_owner,
+                    delegate_revoke.transaction_id,
+                    delegate_revoke.slot,
+                    delegate_revoke.signer,
+                    delegate_revoke.outer_instruction_index,
+                    format_outer_program(delegate_revoke.outer_program_id),
+                    delegate_revoke.original_ix,
+                    format_owner_metadata(account_entry)
+                )?;
+            }
+        }
+        writeln!(&mut writer)?;
+        writeln!(&mut writer, "Delegation Timeline")?;
+        writeln!(
+            &mut writer,
+            "Account Address,Owner,Delegate,Approval Signature,Approval Slot,Approved Amount,Remaining Amount,Live,End,End Signature{}",
+            self.format_owner_metadata_columns()
+        )?;
+        for (account_address, account_entry) in &self.entries_by_token_address {
+            for delegation in account_entry.delegation_timeline() {
+                writeln!(
+                    &mut writer,
+                    "{},{},{},{},{},{},{},{},{},{}{}",
+                    account_address,
+                    account_entry.current_owner,
+                    delegation.approval.new_delegate,
+                    delegation.approval.transaction_id,
+                    delegation.approval.slot,
+                    format_amount(delegation.approval.amount, delegation.approval.decimals),
+                    format_amount(delegation.remaining_amount, delegation.approval.decimals),
+                    delegation.is_live(account_entry.current_delegate),
+                    delegation
+                        .end
+                        .as_ref()
+                        .map(|end| end.reason.to_string())
+                        .unwrap_or_default(),
+                    delegation
+                        .end
+                        .as_ref()
+                        .map(|end| end.transaction_id.to_string())
+                        .unwrap_or_default(),
+                    format_owner_metadata(account_entry)
+                )?;
+            }
+        }
+        writeln!(&mut writer)?;
+        writeln!(&mut writer, "Close Authority Change Transactions")?;
+        writeln!(
+            &mut writer,
+            "Account Address,Owner,Signature,Slot,Signer,New Close Authority,Outer Instruction,Outer Program,JSON Instruction{}",
+            self.format_owner_metadata_columns()
+        )?;
+        for (account_address, account_entry) in &self.entries_by_token_address {
+            for close_authority_change in &account_entry.close_authority_changes {
+                writeln!(
+                    &mut writer,
+                    "{},{},{},{},{},{},{},{},{}{}",
+                    account_address,
+                    account_entry.current_owner,
+                    close_authority_change.transaction_id,
+                    close_authority_change.slot,
+                    close_authority_change.signer,
+                    close_authority_change
+                        .new_close_authority
+                        .map(|close_authority| close_authority.to_string())
+                        .unwrap_or_default(),
+                    close_authority_change.outer_instruction_index,
+                    format_outer_program(close_authority_change.outer_program_id),
+                    close_authority_change.original_ix,
+                    format_owner_metadata(account_entry)
                 )?;
             }
         }
@@ -160,20 +814,25 @@ impl Report {
         writeln!(&mut writer, "Possibly Fraudulent Transfers")?;
         writeln!(
             &mut writer,
-            "Account Address,Owner,Signature,Slot,Signer,Amount,JSON Instruction"
+            "Account Address,Owner,Signature,Slot,Signer,Amount,Balance Change,Outer Instruction,Outer Program,JSON Instruction{}",
+            self.format_owner_metadata_columns()
         )?;
         for (account_address, account_entry) in &self.entries_by_token_address {
             for transfer in &account_entry.possible_delegate_transfers {
                 writeln!(
                     &mut writer,
-                    "{},{},{},{},{},{},{}",
+                    "{},{},{},{},{},{},{},{},{},{}{}",
                     account_address,
                     account_entry.current_owner,
                     transfer.transaction_id,
                     transfer.slot,
                     transfer.signer,
-                    transfer.amount,
-                    transfer.original_ix
+                    format_amount(transfer.amount, transfer.decimals),
+                    format_balance_change(transfer.balance_change, transfer.decimals),
+                    transfer.outer_instruction_index,
+                    format_outer_program(transfer.outer_program_id),
+                    transfer.original_ix,
+                    format_owner_metadata(account_entry)
                 )?;
             }
         }
@@ -182,23 +841,158 @@ impl Report {
         writeln!(&mut writer, "Possibly Fraudulent Burns")?;
         writeln!(
             &mut writer,
-            "Account Address,Owner,Signature,Slot,Signer,Amount,JSON Instruction"
+            "Account Address,Owner,Signature,Slot,Signer,Amount,Balance Change,Outer Instruction,Outer Program,JSON Instruction{}",
+            self.format_owner_metadata_columns()
         )?;
         for (account_address, account_entry) in &self.entries_by_token_address {
             for burn in &account_entry.possible_delegate_burns {
                 writeln!(
                     &mut writer,
-                    "{},{},{},{},{},{},{}",
+                    "{},{},{},{},{},{},{},{},{},{}{}",
                     account_address,
                     account_entry.current_owner,
                     burn.transaction_id,
                     burn.slot,
                     burn.signer,
-                    burn.amount,
-                    burn.original_ix
+                    format_amount(burn.amount, burn.decimals),
+                    format_balance_change(burn.balance_change, burn.decimals),
+                    burn.outer_instruction_index,
+                    format_outer_program(burn.outer_program_id),
+                    burn.original_ix,
+                    format_owner_metadata(account_entry)
+                )?;
+            }
+        }
+
+        writeln!(&mut writer)?;
+        writeln!(&mut writer, "Account Freeze Transactions")?;
+        writeln!(
+            &mut writer,
+            "Account Address,Owner,Signature,Slot,Freeze Authority,Frozen,Outer Instruction,Outer Program,JSON Instruction{}",
+            self.format_owner_metadata_columns()
+        )?;
+        for (account_address, account_entry) in &self.entries_by_token_address {
+            for freeze_change in &account_entry.freeze_changes {
+                writeln!(
+                    &mut writer,
+                    "{},{},{},{},{},{},{},{},{}{}",
+                    account_address,
+                    account_entry.current_owner,
+                    freeze_change.transaction_id,
+                    freeze_change.slot,
+                    freeze_change.signer,
+                    freeze_change.frozen,
+                    freeze_change.outer_instruction_index,
+                    format_outer_program(freeze_change.outer_program_id),
+                    freeze_change.original_ix,
+                    format_owner_metadata(account_entry)
+                )?;
+            }
+        }
+
+        writeln!(&mut writer)?;
+        writeln!(&mut writer, "Multisig Signed Transactions")?;
+        writeln!(
+            &mut writer,
+            "Account Address,Owner,Signature,Multisig,M,N,Members,Signing Members{}",
+            self.format_owner_metadata_columns()
+        )?;
+        for (account_address, account_entry) in &self.entries_by_token_address {
+            for (transaction_id, multisig, multisig_signers) in account_entry.multisig_signatures()
+            {
+                let multisig_config = account_entry.multisigs.get(&multisig);
+                writeln!(
+                    &mut writer,
+                    "{},{},{},{},{},{},{},{}{}",
+                    account_address,
+                    account_entry.current_owner,
+                    transaction_id,
+                    multisig,
+                    multisig_config
+                        .map(|multisig_config| multisig_config.m.to_string())
+                        .unwrap_or_default(),
+                    multisig_config
+                        .map(|multisig_config| multisig_config.n.to_string())
+                        .unwrap_or_default(),
+                    multisig_config
+                        .map(|multisig_config| join_pubkeys(&multisig_config.members))
+                        .unwrap_or_default(),
+                    join_pubkeys(multisig_signers),
+                    format_owner_metadata(account_entry)
+                )?;
+            }
+        }
+
+        writeln!(&mut writer)?;
+        writeln!(&mut writer, "Unrecognized Instructions")?;
+        writeln!(
+            &mut writer,
+            "Account Address,Owner,Signature,Slot,JSON Instruction{}",
+            self.format_owner_metadata_columns()
+        )?;
+        for (account_address, account_entry) in &self.entries_by_token_address {
+            for unknown in &account_entry.unknown_instructions {
+                writeln!(
+                    &mut writer,
+                    "{},{},{},{},{}{}",
+                    account_address,
+                    account_entry.current_owner,
+                    unknown.transaction_id,
+                    unknown.slot,
+                    unknown.original_ix,
+                    format_owner_metadata(account_entry)
+                )?;
+            }
+        }
+
+        writeln!(&mut writer)?;
+        writeln!(&mut writer, "Recognized Instruction Counts")?;
+        writeln!(
+            &mut writer,
+            "Account Address,Owner,Instruction,Count{}",
+            self.format_owner_metadata_columns()
+        )?;
+        for (account_address, account_entry) in &self.entries_by_token_address {
+            for (ix_type, count) in &account_entry.instruction_counts {
+                writeln!(
+                    &mut writer,
+                    "{},{},{},{}{}",
+                    account_address,
+                    account_entry.current_owner,
+                    ix_type,
+                    count,
+                    format_owner_metadata(account_entry)
                 )?;
             }
         }
+
+        // which RPC endpoints the history of each account comes from, since they may not all
+        // have the same history
+        writeln!(&mut writer)?;
+        writeln!(&mut writer, "History Endpoints")?;
+        writeln!(
+            &mut writer,
+            "Account Address,Owner,Transactions,Endpoints{}",
+            self.format_owner_metadata_columns()
+        )?;
+        for (account_address, account_entry) in &self.entries_by_token_address {
+            writeln!(
+                &mut writer,
+                "{},{},{},{}{}",
+                account_address,
+                account_entry.current_owner,
+                account_entry.total_tx_count,
+                csv_field(
+                    &account_entry
+                        .history_endpoints
+                        .iter()
+                        .map(String::as_str)
+                        .collect::<Vec<_>>()
+                        .join(" ")
+                ),
+                format_owner_metadata(account_entry)
+            )?;
+        }
         Ok(())
     }
 }
@@ -206,7 +1000,10 @@ impl Report {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::token::{DelegateBurn, DelegateChange, DelegateTransfer, OwnerChange};
+    use crate::token::{
+        CloseAuthorityChange, DelegateBurn, DelegateChange, DelegateRevoke, DelegateTransfer,
+        OwnerChange, UnknownInstruction,
+    };
     use solana_sdk::{clock::Slot, signature::Signature, signer::keypair::Keypair};
 
     fn new_signature() -> Signature {
@@ -217,9 +1014,16 @@ mod tests {
     fn new_delegate_transfer(slot: Slot) -> DelegateTransfer {
         DelegateTransfer {
             slot,
+            transaction_index: 0,
+            instruction_index: 0,
+            outer_instruction_index: 0,
+            outer_program_id: None,
             transaction_id: new_signature(),
             signer: Pubkey::new_unique(),
-            amount: "100".to_string(),
+            multisig_signers: vec![],
+            amount: 100,
+            decimals: 6,
+            balance_change: Some(-100),
             original_ix: "ix".to_string(),
         }
     }
@@ -227,9 +1031,16 @@ mod tests {
     fn new_delegate_burn(slot: Slot) -> DelegateBurn {
         DelegateBurn {
             slot,
+            transaction_index: 0,
+            instruction_index: 0,
+            outer_instruction_index: 0,
+            outer_program_id: None,
             transaction_id: new_signature(),
             signer: Pubkey::new_unique(),
-            amount: "100".to_string(),
+            multisig_signers: vec![],
+            amount: 100,
+            decimals: 6,
+            balance_change: Some(-100),
             original_ix: "ix".to_string(),
         }
     }
@@ -237,8 +1048,13 @@ mod tests {
     fn new_owner_change(slot: Slot) -> OwnerChange {
         OwnerChange {
             slot,
+            transaction_index: 0,
+            instruction_index: 0,
+            outer_instruction_index: 0,
+            outer_program_id: None,
             transaction_id: new_signature(),
             signer: Pubkey::new_unique(),
+            multisig_signers: vec![],
             new_owner: Pubkey::new_unique(),
             original_ix: "ix".to_string(),
         }
@@ -247,9 +1063,16 @@ mod tests {
     fn new_delegate_change(slot: Slot) -> DelegateChange {
         DelegateChange {
             slot,
+            transaction_index: 0,
+            instruction_index: 0,
+            outer_instruction_index: 0,
+            outer_program_id: None,
             transaction_id: new_signature(),
             signer: Pubkey::new_unique(),
+            multisig_signers: vec![],
             new_delegate: Pubkey::new_unique(),
+            amount: 999,
+            decimals: 6,
             original_ix: "ix".to_string(),
         }
     }
@@ -257,6 +1080,7 @@ mod tests {
     /// A good token account entry has no owner changes ever
     fn good_token_account_entry(owner: Pubkey, mint: Pubkey) -> TokenAccountEntry {
         let mut token_account_entry = TokenAccountEntry::new(owner, mint);
+        token_account_entry.associated = true;
         let slot = 10;
         token_account_entry
             .possible_delegate_transfers
@@ -268,9 +1092,6 @@ mod tests {
             .delegate_changes
             .push(new_delegate_change(slot));
         token_account_entry
-            .all_delegate_addresses
-            .insert(Pubkey::new_unique());
-        token_account_entry
     }
 
     /// A reassigned account entry has had its ownership changed
@@ -292,12 +1113,28 @@ mod tests {
         token_account_entry
             .owner_changes
             .push(new_owner_change(slot));
+        let delegate_change = new_delegate_change(slot);
+        token_account_entry.current_delegate = Some(delegate_change.new_delegate);
+        token_account_entry.delegate_changes.push(delegate_change);
         token_account_entry
-            .delegate_changes
-            .push(new_delegate_change(slot));
-        token_account_entry
-            .all_delegate_addresses
-            .insert(Pubkey::new_unique());
+    }
+
+    /// A revoked delegation account entry has a delegation set by a previous
+    /// owner, which was revoked by the current owner after the owner change
+    fn revoked_delegation_token_account_entry(owner: Pubkey, mint: Pubkey) -> TokenAccountEntry {
+        let mut token_account_entry = open_delegation_token_account_entry(owner, mint);
+        token_account_entry.current_delegate = None;
+        token_account_entry.delegate_revokes.push(DelegateRevoke {
+            slot: 11,
+            transaction_index: 0,
+            instruction_index: 0,
+            outer_instruction_index: 0,
+            outer_program_id: None,
+            transaction_id: new_signature(),
+            signer: owner,
+            multisig_signers: vec![],
+            original_ix: "ix".to_string(),
+        });
         token_account_entry
     }
 
@@ -320,13 +1157,74 @@ mod tests {
         token_account_entry
             .possible_delegate_burns
             .push(delegate_burn);
+        token_account_entry.delegate_changes.push(delegate_change);
+        token_account_entry
+    }
+
+    /// A twice reassigned account entry has a delegation set by the
+    /// intermediate owner, which was used after it handed the account over
+    fn twice_reassigned_token_account_entry(owner: Pubkey, mint: Pubkey) -> TokenAccountEntry {
+        let mut token_account_entry = TokenAccountEntry::new(owner, mint);
+        token_account_entry.associated = true;
+        let first_owner_change = new_owner_change(10);
+        let intermediate_owner = first_owner_change.new_owner;
+        let mut delegate_change = new_delegate_change(11);
+        delegate_change.signer = intermediate_owner;
+        let mut second_owner_change = new_owner_change(12);
+        second_owner_change.signer = intermediate_owner;
+        second_owner_change.new_owner = owner;
+        let mut delegate_transfer = new_delegate_transfer(13);
+        delegate_transfer.signer = delegate_change.new_delegate;
+        token_account_entry.owner_changes.push(second_owner_change);
+        token_account_entry.owner_changes.push(first_owner_change);
         token_account_entry
-            .all_delegate_addresses
-            .insert(delegate_change.new_delegate);
+            .possible_delegate_transfers
+            .push(delegate_transfer);
         token_account_entry.delegate_changes.push(delegate_change);
         token_account_entry
     }
 
+    fn new_close_authority_change(slot: Slot) -> CloseAuthorityChange {
+        CloseAuthorityChange {
+            slot,
+            transaction_index: 0,
+            instruction_index: 0,
+            outer_instruction_index: 0,
+            outer_program_id: None,
+            transaction_id: new_signature(),
+            signer: Pubkey::new_unique(),
+            multisig_signers: vec![],
+            new_close_authority: Some(Pubkey::new_unique()),
+            original_ix: "ix".to_string(),
+        }
+    }
+
+    /// A foreign close authority account entry had its close authority set by
+    /// a previous owner
+    fn foreign_close_authority_token_account_entry(
+        owner: Pubkey,
+        mint: Pubkey,
+    ) -> TokenAccountEntry {
+        let mut token_account_entry = TokenAccountEntry::new(owner, mint);
+        token_account_entry
+            .close_authority_changes
+            .push(new_close_authority_change(10));
+        token_account_entry
+    }
+
+    /// An unrecognized account entry has instructions the audit couldn't classify
+    fn unrecognized_token_account_entry(owner: Pubkey, mint: Pubkey) -> TokenAccountEntry {
+        let mut token_account_entry = TokenAccountEntry::new(owner, mint);
+        token_account_entry
+            .unknown_instructions
+            .push(UnknownInstruction {
+                slot: 10,
+                transaction_id: new_signature(),
+                original_ix: "ix".to_string(),
+            });
+        token_account_entry
+    }
+
     #[test]
     fn detail_good() {
         let owner = Pubkey::new_unique();
@@ -340,6 +1238,31 @@ mod tests {
         println!("{}", converted);
     }
 
+    #[test]
+    fn detail_history_endpoints() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let address = Pubkey::new_unique();
+        let mut entry = good_token_account_entry(owner, mint);
+        entry.total_tx_count = 3;
+        entry
+            .history_endpoints
+            .insert("https://archive.example.com/".to_string());
+        entry
+            .history_endpoints
+            .insert("https://api.mainnet-beta.solana.com".to_string());
+        let mut report = Report::new();
+        report.entries_by_token_address.insert(address, entry);
+        let mut buffer: Vec<u8> = vec![];
+        report.detail(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        println!("{}", converted);
+        assert!(converted.contains(&format!(
+            "{},{},3,https://api.mainnet-beta.solana.com https://archive.example.com/\n",
+            address, owner
+        )));
+    }
+
     #[test]
     fn detail_reassigned() {
         let owner = Pubkey::new_unique();
@@ -377,6 +1300,8 @@ mod tests {
         report.detail(&mut buffer).unwrap();
         let converted = std::str::from_utf8(&buffer).unwrap();
         println!("{}", converted);
+        // 100 raw with 6 decimals
+        assert!(converted.contains(",0.0001,"));
     }
 
     #[test]
@@ -392,6 +1317,136 @@ mod tests {
         println!("{}", converted);
     }
 
+    #[test]
+    fn summary_auxiliary() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let mut report = Report::new();
+        let good_address = Pubkey::new_unique();
+        let mut good = good_token_account_entry(owner, mint);
+        good.associated = false;
+        report.entries_by_token_address.insert(good_address, good);
+        let reassigned_address = Pubkey::new_unique();
+        report.entries_by_token_address.insert(
+            reassigned_address,
+            reassigned_token_account_entry(owner, mint),
+        );
+        let associated_address = Pubkey::new_unique();
+        report
+            .entries_by_token_address
+            .insert(associated_address, good_token_account_entry(owner, mint));
+        let mut buffer: Vec<u8> = vec![];
+        report.summary(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        println!("{}", converted);
+        assert!(converted.contains(&format!(
+            "Notice - auxiliary account,{},{},{},,,,current,auxiliary\n",
+            good_address, owner, owner
+        )));
+        assert!(converted.contains(&format!(
+            "Warning - reassigned auxiliary account,{},",
+            reassigned_address
+        )));
+        assert!(converted.contains(&format!(
+            "Safe,{},{},{},,,,current,associated\n",
+            associated_address, owner, owner
+        )));
+    }
+
+    #[test]
+    fn summary_incomplete() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let mut report = Report::new();
+        let incomplete_address = Pubkey::new_unique();
+        let mut incomplete = good_token_account_entry(owner, mint);
+        incomplete.mark_incomplete("couldn't fetch transaction 1: timed out, 30s".to_string());
+        incomplete.mark_incomplete("couldn't fetch transaction 2: timed out".to_string());
+        report
+            .entries_by_token_address
+            .insert(incomplete_address, incomplete);
+        let incomplete_owner = Pubkey::new_unique();
+        report.incomplete_owners.push(IncompleteOwner {
+            owner: incomplete_owner,
+            error: "couldn't list the token accounts: node is unhealthy".to_string(),
+            owner_metadata: vec![],
+        });
+
+        let mut buffer: Vec<u8> = vec![];
+        report.summary(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        println!("{}", converted);
+        // not safe as far as anyone knows
+        assert!(!converted.contains("Safe"));
+        assert!(converted.contains(&format!(
+            "Incomplete - history not fully fetched,{},{},,,,,current,associated\n",
+            incomplete_address, owner
+        )));
+
+        let mut buffer: Vec<u8> = vec![];
+        report.incomplete(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        println!("{}", converted);
+        // the first error is kept, quoted for its comma
+        assert!(converted.contains(&format!(
+            "{},{},{},\"couldn't fetch transaction 1: timed out, 30s\"\n",
+            incomplete_address, owner, mint
+        )));
+        assert!(converted.contains(&format!(
+            ",{},,couldn't list the token accounts: node is unhealthy\n",
+            incomplete_owner
+        )));
+    }
+
+    #[test]
+    fn summary_incomplete_account() {
+        let token_address = Pubkey::new_unique();
+        let mut report = Report::new();
+        report.incomplete_accounts.push(IncompleteAccount {
+            token_address,
+            error: format!("couldn't fetch token account {}: timed out", token_address),
+        });
+
+        let mut buffer: Vec<u8> = vec![];
+        report.summary(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        assert!(converted.contains(&format!(
+            "Incomplete - account not fetched,{},,,,,,,\n",
+            token_address
+        )));
+
+        let mut buffer: Vec<u8> = vec![];
+        report.incomplete(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        assert!(converted.contains(&format!(
+            "{},,,couldn't fetch token account {}: timed out\n",
+            token_address, token_address
+        )));
+    }
+
+    #[test]
+    fn incomplete_account_owner_metadata_columns() {
+        let token_address = Pubkey::new_unique();
+        let mut report = Report::new();
+        report.owner_metadata_columns = vec!["customer_id".to_string(), "label".to_string()];
+        report.incomplete_accounts.push(IncompleteAccount {
+            token_address,
+            error: "timed out".to_string(),
+        });
+
+        let mut buffer: Vec<u8> = vec![];
+        report.summary(&mut buffer).unwrap();
+        report.incomplete(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        println!("{}", converted);
+        // no owner is known, so its columns are left empty, but the rows are as wide as the header
+        assert!(converted.contains(&format!(
+            "Incomplete - account not fetched,{},,,,,,,,,\n",
+            token_address
+        )));
+        assert!(converted.contains(&format!("{},,,timed out,,\n", token_address)));
+    }
+
     #[test]
     fn summary_reassigned() {
         let owner = Pubkey::new_unique();
@@ -416,6 +1471,22 @@ mod tests {
         report.summary(&mut buffer).unwrap();
         let converted = std::str::from_utf8(&buffer).unwrap();
         println!("{}", converted);
+        assert!(converted.contains("Warning - clear delegation immediately"));
+    }
+
+    #[test]
+    fn summary_revoked_delegation() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let entry = revoked_delegation_token_account_entry(owner, mint);
+        let mut report = Report::new();
+        report.entries_by_token_address.insert(owner, entry);
+        let mut buffer: Vec<u8> = vec![];
+        report.summary(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        println!("{}", converted);
+        assert!(converted.contains("Safe - delegation no longer active"));
+        assert!(!converted.contains("Warning - clear delegation immediately"));
     }
 
     #[test]
@@ -429,5 +1500,261 @@ mod tests {
         report.summary(&mut buffer).unwrap();
         let converted = std::str::from_utf8(&buffer).unwrap();
         println!("{}", converted);
+        assert!(converted.contains("Danger - possible fraud"));
+    }
+
+    #[test]
+    fn summary_twice_reassigned() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let entry = twice_reassigned_token_account_entry(owner, mint);
+        let owner_periods = entry.owner_periods();
+        assert_eq!(owner_periods.len(), 3);
+        assert_eq!(owner_periods[0].owner, entry.owner_changes[1].signer);
+        assert_eq!(owner_periods[2].owner, owner);
+        let intermediate_owner = owner_periods[1].owner;
+        let transfer_id = entry.possible_delegate_transfers[0].transaction_id;
+        let mut report = Report::new();
+        report.entries_by_token_address.insert(owner, entry);
+        let mut buffer: Vec<u8> = vec![];
+        report.summary(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        println!("{}", converted);
+        // the delegation is the intermediate owner's own, until they hand over
+        assert!(converted.contains(&format!(
+            "Safe - reassignment only,{},{},{},",
+            owner, owner, intermediate_owner
+        )));
+        // but it's attributed to the current owner's period once used
+        assert!(converted.contains(&format!(
+            "Danger - possible fraud,{},{},{},",
+            owner, owner, owner
+        )));
+        assert!(converted.contains(&transfer_id.to_string()));
+    }
+
+    #[test]
+    fn detail_twice_reassigned() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let entry = twice_reassigned_token_account_entry(owner, mint);
+        let mut report = Report::new();
+        report.entries_by_token_address.insert(owner, entry);
+        let mut buffer: Vec<u8> = vec![];
+        report.detail(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        println!("{}", converted);
+        assert!(converted.contains("Ownership Periods"));
+        // one approval in the intermediate period, one transfer in the current one
+        assert!(converted.contains(",1,0,0\n"));
+        assert!(converted.contains(",0,1,0\n"));
+    }
+
+    #[test]
+    fn losses_fraudulent() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let mut report = Report::new();
+        for _ in 0..2 {
+            let mut entry = fraudulent_token_account_entry(owner, mint);
+            entry.decimals = 6;
+            report
+                .entries_by_token_address
+                .insert(Pubkey::new_unique(), entry);
+        }
+        report
+            .entries_by_token_address
+            .insert(Pubkey::new_unique(), good_token_account_entry(owner, mint));
+        let mut buffer: Vec<u8> = vec![];
+        report.losses(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        println!("{}", converted);
+        // a transfer and a burn of 100 raw each, in two accounts
+        assert!(converted.contains(&format!("{},{},0.0004,2", owner, mint)));
+        assert!(converted.contains(&format!("{},0.0004,2", mint)));
+    }
+
+    #[test]
+    fn exposure_mint_wide() {
+        let mint = Pubkey::new_unique();
+        let mut report = Report::new();
+        let mut open_delegation = open_delegation_token_account_entry(Pubkey::new_unique(), mint);
+        open_delegation.decimals = 6;
+        open_delegation.current_amount = 500;
+        report
+            .entries_by_token_address
+            .insert(Pubkey::new_unique(), open_delegation);
+        let mut fraudulent = fraudulent_token_account_entry(Pubkey::new_unique(), mint);
+        fraudulent.decimals = 6;
+        report
+            .entries_by_token_address
+            .insert(Pubkey::new_unique(), fraudulent);
+        // its mint couldn't be fetched, so it has no decimals, whichever order the accounts come in
+        report.entries_by_token_address.insert(
+            Pubkey::new_unique(),
+            good_token_account_entry(Pubkey::new_unique(), mint),
+        );
+        let mut buffer: Vec<u8> = vec![];
+        report.exposure(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        println!("{}", converted);
+        // the open delegation can take 999 raw, but there are only 500 left
+        assert!(converted.contains(&format!("{},3,2,1,0.0002,1,0.0005,0,0", mint)));
+    }
+
+    #[test]
+    fn outbound_reassignments() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let mut report = Report::new();
+
+        // handed to an intermediate owner, who handed it on
+        let token_address = Pubkey::new_unique();
+        let entry = twice_reassigned_token_account_entry(owner, mint);
+        let first_owner_change = entry.owner_changes[1].clone();
+        report.outbound_reassignments.push(OutboundReassignment {
+            token_address,
+            previous_owner: first_owner_change.signer,
+            new_owner: first_owner_change.new_owner,
+            slot: first_owner_change.slot,
+            transaction_id: first_owner_change.transaction_id,
+            owner_metadata: vec![],
+        });
+        report.entries_by_token_address.insert(token_address, entry);
+
+        // closed by the new owner since
+        let closed_token_address = Pubkey::new_unique();
+        let mut entry = reassigned_token_account_entry(owner, mint);
+        entry.closed = true;
+        let owner_change = entry.owner_changes[0].clone();
+        report.outbound_reassignments.push(OutboundReassignment {
+            token_address: closed_token_address,
+            previous_owner: owner_change.signer,
+            new_owner: owner_change.new_owner,
+            slot: owner_change.slot,
+            transaction_id: owner_change.transaction_id,
+            owner_metadata: vec![],
+        });
+        report
+            .entries_by_token_address
+            .insert(closed_token_address, entry);
+
+        let mut buffer: Vec<u8> = vec![];
+        report.outbound(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        println!("{}", converted);
+        assert!(converted.contains(&format!(
+            "reassigned again,{},{},{},{},{},10,{},{},0",
+            token_address,
+            mint,
+            first_owner_change.signer,
+            first_owner_change.new_owner,
+            first_owner_change.transaction_id,
+            owner,
+            owner
+        )));
+        assert!(converted.contains(&format!("closed,{},", closed_token_address)));
+    }
+
+    #[test]
+    fn owner_metadata_columns() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let mut entry = fraudulent_token_account_entry(owner, mint);
+        entry.owner_metadata = vec!["42".to_string(), "hot, wallet".to_string()];
+        let mut report = Report::new();
+        report.owner_metadata_columns = vec!["customer_id".to_string(), "label".to_string()];
+        report.entries_by_token_address.insert(owner, entry);
+
+        let mut buffer: Vec<u8> = vec![];
+        report.summary(&mut buffer).unwrap();
+        report.detail(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        println!("{}", converted);
+        for line in converted.lines() {
+            if line.starts_with("Status,") || line.starts_with("Account Address,") {
+                assert!(line.ends_with(",customer_id,label"));
+            } else if line.contains(&owner.to_string()) {
+                assert!(line.ends_with(",42,\"hot, wallet\""));
+            }
+        }
+    }
+
+    #[test]
+    fn summary_unrecognized() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let entry = unrecognized_token_account_entry(owner, mint);
+        let mut report = Report::new();
+        report.entries_by_token_address.insert(owner, entry);
+        let mut buffer: Vec<u8> = vec![];
+        report.summary(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        println!("{}", converted);
+        assert!(converted.contains(&format!(
+            "Warning - unrecognized instructions,{},{},,,,,current,auxiliary\n",
+            owner, owner
+        )));
+    }
+
+    #[test]
+    fn detail_foreign_close_authority() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let entry = foreign_close_authority_token_account_entry(owner, mint);
+        let close_authority_change = entry.close_authority_changes[0].clone();
+        let mut report = Report::new();
+        report.entries_by_token_address.insert(owner, entry);
+        let mut buffer: Vec<u8> = vec![];
+        report.detail(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        println!("{}", converted);
+        let section = converted
+            .split("Close Authority Change Transactions\n")
+            .nth(1)
+            .unwrap();
+        assert!(section.contains(&format!(
+            "{},{},{},10,{},{},0,,ix\n",
+            owner,
+            owner,
+            close_authority_change.transaction_id,
+            close_authority_change.signer,
+            close_authority_change.new_close_authority.unwrap()
+        )));
+    }
+
+    #[test]
+    fn summary_foreign_close_authority() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let entry = foreign_close_authority_token_account_entry(owner, mint);
+        let mut report = Report::new();
+        report.entries_by_token_address.insert(owner, entry);
+        let mut buffer: Vec<u8> = vec![];
+        report.summary(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        println!("{}", converted);
+        assert!(converted.contains(&format!(
+            "Warning - reset close authority immediately,{},{},,,,,current,auxiliary\n",
+            owner, owner
+        )));
+    }
+
+    #[test]
+    fn summary_close_authority_reset_by_owner() {
+        let owner = Pubkey::new_unique();
+        let mint = Pubkey::new_unique();
+        let mut entry = foreign_close_authority_token_account_entry(owner, mint);
+        let mut reset = new_close_authority_change(11);
+        reset.signer = owner;
+        reset.new_close_authority = None;
+        entry.close_authority_changes.push(reset);
+        let mut report = Report::new();
+        report.entries_by_token_address.insert(owner, entry);
+        let mut buffer: Vec<u8> = vec![];
+        report.summary(&mut buffer).unwrap();
+        let converted = std::str::from_utf8(&buffer).unwrap();
+        println!("{}", converted);
+        assert!(!converted.contains("close authority"));
     }
 }
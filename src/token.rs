@@ -3,29 +3,88 @@ use {
     solana_sdk::{clock::Slot, pubkey::Pubkey, signature::Signature},
 };
 
+/// Where an event happened in the ledger: slot, transaction index inside the
+/// block and instruction index inside the transaction
+pub type EventOrder = (Slot, usize, usize);
+
+pub trait TokenEvent {
+    fn slot(&self) -> Slot;
+    fn transaction_id(&self) -> Signature;
+    fn order(&self) -> EventOrder;
+    fn set_transaction_index(&mut self, transaction_index: usize);
+}
+
+macro_rules! impl_token_event {
+    ($($event:ty),*) => {
+        $(
+            impl TokenEvent for $event {
+                fn slot(&self) -> Slot {
+                    self.slot
+                }
+
+                fn transaction_id(&self) -> Signature {
+                    self.transaction_id
+                }
+
+                fn order(&self) -> EventOrder {
+                    (self.slot, self.transaction_index, self.instruction_index)
+                }
+
+                fn set_transaction_index(&mut self, transaction_index: usize) {
+                    self.transaction_index = transaction_index;
+                }
+            }
+        )*
+    };
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct DelegateTransfer {
     pub slot: Slot,
+    pub transaction_index: usize,
+    pub instruction_index: usize,
+    // index of the top-level instruction, the same as above unless invoked by CPI
+    pub outer_instruction_index: usize,
+    // program which invoked the instruction by CPI, `None` for top-level instructions
+    pub outer_program_id: Option<Pubkey>,
     pub transaction_id: Signature,
     pub signer: Pubkey,
-    pub amount: String,
+    // the members which signed, if the signer is an spl-token multisig
+    pub multisig_signers: Vec<Pubkey>,
+    // raw amount, the mint decimals are only applied when rendering the report
+    pub amount: u64,
+    pub decimals: u8,
+    // raw change of the audited account balance over the whole transaction, from the status meta
+    pub balance_change: Option<i128>,
     pub original_ix: String,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct DelegateBurn {
     pub slot: Slot,
+    pub transaction_index: usize,
+    pub instruction_index: usize,
+    pub outer_instruction_index: usize,
+    pub outer_program_id: Option<Pubkey>,
     pub transaction_id: Signature,
     pub signer: Pubkey,
-    pub amount: String,
+    pub multisig_signers: Vec<Pubkey>,
+    pub amount: u64,
+    pub decimals: u8,
+    pub balance_change: Option<i128>,
     pub original_ix: String,
 }
 
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct OwnerChange {
     pub slot: Slot,
+    pub transaction_index: usize,
+    pub instruction_index: usize,
+    pub outer_instruction_index: usize,
+    pub outer_program_id: Option<Pubkey>,
     pub transaction_id: Signature,
     pub signer: Pubkey,
+    pub multisig_signers: Vec<Pubkey>,
     pub new_owner: Pubkey,
     pub original_ix: String,
 }
@@ -33,19 +92,314 @@ pub struct OwnerChange {
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct DelegateChange {
     pub slot: Slot,
+    pub transaction_index: usize,
+    pub instruction_index: usize,
+    pub outer_instruction_index: usize,
+    pub outer_program_id: Option<Pubkey>,
     pub transaction_id: Signature,
     pub signer: Pubkey,
+    pub multisig_signers: Vec<Pubkey>,
     pub new_delegate: Pubkey,
+    pub amount: u64,
+    pub decimals: u8,
+    pub original_ix: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DelegateRevoke {
+    pub slot: Slot,
+    pub transaction_index: usize,
+    pub instruction_index: usize,
+    pub outer_instruction_index: usize,
+    pub outer_program_id: Option<Pubkey>,
+    pub transaction_id: Signature,
+    pub signer: Pubkey,
+    pub multisig_signers: Vec<Pubkey>,
+    pub original_ix: String,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum DelegationEndReason {
+    Revoked,
+    Replaced,
+    Depleted,
+    OwnerChanged,
+}
+
+impl std::fmt::Display for DelegationEndReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DelegationEndReason::Revoked => write!(f, "revoked"),
+            DelegationEndReason::Replaced => write!(f, "replaced"),
+            DelegationEndReason::Depleted => write!(f, "depleted"),
+            DelegationEndReason::OwnerChanged => write!(f, "owner changed"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct DelegationEnd {
+    pub reason: DelegationEndReason,
+    pub slot: Slot,
+    pub transaction_index: usize,
+    pub instruction_index: usize,
+    pub transaction_id: Signature,
+}
+
+impl DelegationEnd {
+    fn new(reason: DelegationEndReason, event: &dyn TokenEvent) -> Self {
+        let (slot, transaction_index, instruction_index) = event.order();
+        Self {
+            reason,
+            slot,
+            transaction_index,
+            instruction_index,
+            transaction_id: event.transaction_id(),
+        }
+    }
+}
+
+/// A single approval, from the approve instruction until the delegate can't
+/// move the funds anymore
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Delegation {
+    pub approval: DelegateChange,
+    pub remaining_amount: u64,
+    pub transfers: Vec<DelegateTransfer>,
+    pub burns: Vec<DelegateBurn>,
+    pub end: Option<DelegationEnd>,
+}
+
+impl Delegation {
+    fn new(approval: &DelegateChange) -> Self {
+        Self {
+            approval: approval.clone(),
+            remaining_amount: approval.amount,
+            transfers: vec![],
+            burns: vec![],
+            end: None,
+        }
+    }
+
+    /// The owner change reset is only assumed, because token programs
+    /// deployed before the fix for this incident didn't reset delegates on
+    /// owner change. So the delegation is still considered live if the
+    /// account currently has the same delegate.
+    pub fn is_live(&self, current_delegate: Option<Pubkey>) -> bool {
+        match &self.end {
+            None => true,
+            Some(end) => {
+                end.reason == DelegationEndReason::OwnerChanged
+                    && current_delegate == Some(self.approval.new_delegate)
+            }
+        }
+    }
+
+    /// Whether the delegation had definitely ended before the given position
+    pub fn ended_before(&self, order: EventOrder) -> bool {
+        self.end
+            .as_ref()
+            .map(|end| end.order() < order)
+            .unwrap_or(false)
+    }
+
+    /// Transfers and burns by this delegation from `since` until right before
+    /// `until`, if any
+    pub fn used_transaction_ids_between(
+        &self,
+        since: EventOrder,
+        until: Option<EventOrder>,
+    ) -> Vec<Signature> {
+        let is_between =
+            |order: EventOrder| order >= since && until.map(|until| order < until).unwrap_or(true);
+        self.burns
+            .iter()
+            .filter(|burn| is_between(burn.order()))
+            .map(|burn| burn.transaction_id)
+            .chain(
+                self.transfers
+                    .iter()
+                    .filter(|transfer| is_between(transfer.order()))
+                    .map(|transfer| transfer.transaction_id),
+            )
+            .collect()
+    }
+
+    fn use_amount(&mut self, amount: u64, event: &dyn TokenEvent) {
+        self.remaining_amount = self.remaining_amount.saturating_sub(amount);
+        if self.remaining_amount == 0 {
+            self.end = Some(DelegationEnd::new(DelegationEndReason::Depleted, event));
+        }
+    }
+}
+
+enum DelegationEvent<'a> {
+    Approve(&'a DelegateChange),
+    Revoke(&'a DelegateRevoke),
+    OwnerChange(&'a OwnerChange),
+    Transfer(&'a DelegateTransfer),
+    Burn(&'a DelegateBurn),
+}
+
+impl DelegationEvent<'_> {
+    fn as_token_event(&self) -> &dyn TokenEvent {
+        match self {
+            DelegationEvent::Approve(event) => *event,
+            DelegationEvent::Revoke(event) => *event,
+            DelegationEvent::OwnerChange(event) => *event,
+            DelegationEvent::Transfer(event) => *event,
+            DelegationEvent::Burn(event) => *event,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct CloseAuthorityChange {
+    pub slot: Slot,
+    pub transaction_index: usize,
+    pub instruction_index: usize,
+    pub outer_instruction_index: usize,
+    pub outer_program_id: Option<Pubkey>,
+    pub transaction_id: Signature,
+    pub signer: Pubkey,
+    pub multisig_signers: Vec<Pubkey>,
+    pub new_close_authority: Option<Pubkey>,
+    pub original_ix: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct FreezeChange {
+    pub slot: Slot,
+    pub transaction_index: usize,
+    pub instruction_index: usize,
+    pub outer_instruction_index: usize,
+    pub outer_program_id: Option<Pubkey>,
+    pub transaction_id: Signature,
+    pub signer: Pubkey,
+    pub multisig_signers: Vec<Pubkey>,
+    pub frozen: bool,
     pub original_ix: String,
 }
 
+/// An SPL Token instruction the audit couldn't classify, kept so that coverage
+/// gaps show up in the report instead of aborting the run
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct UnknownInstruction {
+    pub slot: Slot,
+    pub transaction_id: Signature,
+    pub original_ix: String,
+}
+
+impl_token_event!(
+    DelegateTransfer,
+    DelegateBurn,
+    OwnerChange,
+    DelegateChange,
+    DelegateRevoke,
+    CloseAuthorityChange,
+    FreezeChange,
+    DelegationEnd
+);
+
+/// A stretch of the account history during which it had the same owner
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct OwnerPeriod {
+    pub owner: Pubkey,
+    // the owner change which started this period, `None` for the earliest known owner
+    pub start: Option<OwnerChange>,
+    // the position of the owner change which ended this period, `None` for the current owner
+    pub end: Option<EventOrder>,
+}
+
+/// Index of the ownership period the event at the given position happened in
+pub fn owner_period_index(owner_periods: &[OwnerPeriod], order: EventOrder) -> usize {
+    owner_periods
+        .iter()
+        .rposition(|owner_period| {
+            owner_period
+                .start
+                .as_ref()
+                .map(|owner_change| owner_change.order() <= order)
+                .unwrap_or(true)
+        })
+        .unwrap_or(0)
+}
+
+/// A delegation which survived into an ownership period of someone other than
+/// its approver
+pub struct InheritedDelegation<'a> {
+    pub period_index: usize,
+    pub delegation: &'a Delegation,
+    // transfers and burns by the delegation during the period
+    pub used_transaction_ids: Vec<Signature>,
+}
+
+/// Tokens taken from the account by inherited delegations
+#[derive(Clone, Debug, Default)]
+pub struct Loss {
+    // raw amount, from the balance changes of the transactions below
+    pub amount: u64,
+    pub transaction_ids: Vec<Signature>,
+    // transactions without token balances in their status meta
+    pub unquantified_transaction_ids: Vec<Signature>,
+}
+
+/// M-of-N configuration of an spl-token multisig authority
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct MultisigConfig {
+    pub m: u8,
+    pub n: u8,
+    pub members: Vec<Pubkey>,
+}
+
+/// A token account which an audited wallet handed to another owner, found in
+/// the wallet's own transaction history
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct OutboundReassignment {
+    pub token_address: Pubkey,
+    pub previous_owner: Pubkey,
+    pub new_owner: Pubkey,
+    pub slot: Slot,
+    pub transaction_id: Signature,
+    // values of the extra columns about the previous owner from the owners file
+    pub owner_metadata: Vec<String>,
+}
+
+/// An audited owner whose token accounts or transaction history couldn't be
+/// fetched, even after retries
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct IncompleteOwner {
+    pub owner: Pubkey,
+    pub error: String,
+    // values of the extra columns about the owner from the owners file
+    pub owner_metadata: Vec<String>,
+}
+
+/// A token account given to audit which couldn't be fetched, so neither its
+/// owner nor its mint is known
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct IncompleteAccount {
+    pub token_address: Pubkey,
+    pub error: String,
+}
+
 #[derive(Clone, Debug, Default, Deserialize, Serialize)]
 pub struct TokenAccountEntry {
     pub current_owner: Pubkey,
     pub mint: Pubkey,
-    // intra slot tx order can't be guranteed.... so there is no perfect way to precisely track the
-    // latest delegate_address, so we need to collect them all and detect possible attempts later
-    pub all_delegate_addresses: std::collections::BTreeSet<Pubkey>,
+    // found in the owner's transaction history rather than among its current accounts
+    pub historical: bool,
+    // raw balance at the time of the audit
+    pub current_amount: u64,
+    // gone at the time of the audit, so only its history is left
+    pub closed: bool,
+    // the address is the associated token account of the current owner and mint, rather than an
+    // auxiliary account, which is what the attackers created and reassigned to their victims
+    pub associated: bool,
+    // values of the extra columns about the owner from the owners file
+    pub owner_metadata: Vec<String>,
+    // decimals of the mint, for instructions which don't carry them
+    pub decimals: u8,
     pub total_tx_count: usize,
     pub scanned_tx_count: usize,
     pub scanned_spl_token_ix_count: usize,
@@ -54,6 +408,22 @@ pub struct TokenAccountEntry {
     pub possible_delegate_burns: Vec<DelegateBurn>,
     pub owner_changes: Vec<OwnerChange>,
     pub delegate_changes: Vec<DelegateChange>,
+    pub delegate_revokes: Vec<DelegateRevoke>,
+    // the delegate of the account at the time of the audit
+    pub current_delegate: Option<Pubkey>,
+    // unlike the other changes, this also includes the ones signed by the current owner, so that
+    // a foreign close authority which was reset later isn't reported
+    pub close_authority_changes: Vec<CloseAuthorityChange>,
+    pub freeze_changes: Vec<FreezeChange>,
+    // multisigs which signed for this account, by address
+    pub multisigs: std::collections::BTreeMap<Pubkey, MultisigConfig>,
+    pub instruction_counts: std::collections::BTreeMap<String, usize>,
+    pub unknown_instructions: Vec<UnknownInstruction>,
+    // the RPC endpoints which answered for the history of the account
+    pub history_endpoints: std::collections::BTreeSet<String>,
+    // the first error which left part of the history unfetched, even after retries, in which case
+    // the findings may be missing some of it
+    pub incomplete: Option<String>,
 }
 
 impl TokenAccountEntry {
@@ -64,6 +434,584 @@ impl TokenAccountEntry {
             ..Self::default()
         }
     }
-    // implement logic here to match any recognized delegate_address against delegate_transfers and
-    // delegate_burns
+
+    /// Record that part of the history couldn't be fetched, keeping the first
+    /// error
+    pub fn mark_incomplete(&mut self, error: String) {
+        self.incomplete.get_or_insert(error);
+    }
+
+    /// The chain of owners, from the earliest known one to the current one
+    pub fn owner_periods(&self) -> Vec<OwnerPeriod> {
+        let mut owner_changes = self.owner_changes.iter().collect::<Vec<_>>();
+        owner_changes.sort_by_key(|owner_change| owner_change.order());
+
+        let mut owner_periods = vec![OwnerPeriod {
+            // whoever signed the first owner change was the owner before it
+            owner: owner_changes
+                .first()
+                .map(|owner_change| owner_change.signer)
+                .unwrap_or(self.current_owner),
+            start: None,
+            end: None,
+        }];
+        for owner_change in owner_changes {
+            if let Some(owner_period) = owner_periods.last_mut() {
+                owner_period.end = Some(owner_change.order());
+            }
+            owner_periods.push(OwnerPeriod {
+                owner: owner_change.new_owner,
+                start: Some(owner_change.clone()),
+                end: None,
+            });
+        }
+        owner_periods
+    }
+
+    /// Whether the signer is the current owner, or a multisig which has the
+    /// current owner as one of its members
+    pub fn is_current_owner(&self, signer: &Pubkey) -> bool {
+        *signer == self.current_owner
+            || self
+                .multisigs
+                .get(signer)
+                .map(|multisig| multisig.members.contains(&self.current_owner))
+                .unwrap_or(false)
+    }
+
+    /// Every event signed by a multisig, as transaction id, multisig address
+    /// and signing members
+    pub fn multisig_signatures(&self) -> Vec<(Signature, Pubkey, &[Pubkey])> {
+        let events = self
+            .possible_delegate_transfers
+            .iter()
+            .map(|event| (event.transaction_id, event.signer, &event.multisig_signers))
+            .chain(
+                self.possible_delegate_burns
+                    .iter()
+                    .map(|event| (event.transaction_id, event.signer, &event.multisig_signers)),
+            )
+            .chain(
+                self.owner_changes
+                    .iter()
+                    .map(|event| (event.transaction_id, event.signer, &event.multisig_signers)),
+            )
+            .chain(
+                self.delegate_changes
+                    .iter()
+                    .map(|event| (event.transaction_id, event.signer, &event.multisig_signers)),
+            )
+            .chain(
+                self.delegate_revokes
+                    .iter()
+                    .map(|event| (event.transaction_id, event.signer, &event.multisig_signers)),
+            )
+            .chain(
+                self.close_authority_changes
+                    .iter()
+                    .map(|event| (event.transaction_id, event.signer, &event.multisig_signers)),
+            )
+            .chain(
+                self.freeze_changes
+                    .iter()
+                    .map(|event| (event.transaction_id, event.signer, &event.multisig_signers)),
+            );
+        events
+            .filter(|(_transaction_id, _signer, multisig_signers)| !multisig_signers.is_empty())
+            .map(|(transaction_id, signer, multisig_signers)| {
+                (transaction_id, signer, multisig_signers.as_slice())
+            })
+            .collect()
+    }
+
+    /// The close authority change which is still in effect, if any
+    pub fn latest_close_authority_change(&self) -> Option<&CloseAuthorityChange> {
+        self.close_authority_changes
+            .iter()
+            .max_by_key(|close_authority_change| close_authority_change.order())
+    }
+
+    fn events(&self) -> impl Iterator<Item = &dyn TokenEvent> {
+        fn as_token_event<E: TokenEvent>(event: &E) -> &dyn TokenEvent {
+            event
+        }
+        self.possible_delegate_transfers
+            .iter()
+            .map(as_token_event)
+            .chain(self.possible_delegate_burns.iter().map(as_token_event))
+            .chain(self.owner_changes.iter().map(as_token_event))
+            .chain(self.delegate_changes.iter().map(as_token_event))
+            .chain(self.delegate_revokes.iter().map(as_token_event))
+            .chain(self.close_authority_changes.iter().map(as_token_event))
+            .chain(self.freeze_changes.iter().map(as_token_event))
+    }
+
+    fn events_mut(&mut self) -> impl Iterator<Item = &mut dyn TokenEvent> {
+        fn as_token_event<E: TokenEvent>(event: &mut E) -> &mut dyn TokenEvent {
+            event
+        }
+        self.possible_delegate_transfers
+            .iter_mut()
+            .map(as_token_event)
+            .chain(self.possible_delegate_burns.iter_mut().map(as_token_event))
+            .chain(self.owner_changes.iter_mut().map(as_token_event))
+            .chain(self.delegate_changes.iter_mut().map(as_token_event))
+            .chain(self.delegate_revokes.iter_mut().map(as_token_event))
+            .chain(self.close_authority_changes.iter_mut().map(as_token_event))
+            .chain(self.freeze_changes.iter_mut().map(as_token_event))
+    }
+
+    /// Slots with events from more than one transaction, which can only be
+    /// ordered by the position of the transactions inside the block
+    pub fn slots_with_unordered_transactions(&self) -> Vec<Slot> {
+        let mut transactions_by_slot =
+            std::collections::BTreeMap::<Slot, std::collections::HashSet<Signature>>::new();
+        for event in self.events() {
+            transactions_by_slot
+                .entry(event.slot())
+                .or_default()
+                .insert(event.transaction_id());
+        }
+        transactions_by_slot
+            .into_iter()
+            .filter(|(_slot, transactions)| transactions.len() > 1)
+            .map(|(slot, _transactions)| slot)
+            .collect()
+    }
+
+    pub fn set_transaction_indexes(
+        &mut self,
+        slot: Slot,
+        transaction_indexes: &std::collections::HashMap<Signature, usize>,
+    ) {
+        for event in self.events_mut().filter(|event| event.slot() == slot) {
+            if let Some(transaction_index) = transaction_indexes.get(&event.transaction_id()) {
+                event.set_transaction_index(*transaction_index);
+            }
+        }
+    }
+
+    /// Replay approvals, revokes, owner changes and delegate transfers and
+    /// burns in order, matching each transfer and burn against the delegation
+    /// which was live at the time
+    pub fn delegation_timeline(&self) -> Vec<Delegation> {
+        // the order of this chain decides the order of events which couldn't be
+        // positioned inside their slot
+        let mut events = self
+            .delegate_changes
+            .iter()
+            .map(DelegationEvent::Approve)
+            .chain(self.delegate_revokes.iter().map(DelegationEvent::Revoke))
+            .chain(self.owner_changes.iter().map(DelegationEvent::OwnerChange))
+            .chain(
+                self.possible_delegate_transfers
+                    .iter()
+                    .map(DelegationEvent::Transfer),
+            )
+            .chain(
+                self.possible_delegate_burns
+                    .iter()
+                    .map(DelegationEvent::Burn),
+            )
+            .collect::<Vec<_>>();
+        events.sort_by_key(|event| event.as_token_event().order());
+
+        let mut delegations: Vec<Delegation> = vec![];
+        let mut live = Option::<usize>::None;
+        // a delegation ended by an owner change, which can be revived if its
+        // delegate shows up again (see `Delegation::is_live()`)
+        let mut reset_by_owner_change = Option::<usize>::None;
+        for event in events {
+            let end = |reason| Some(DelegationEnd::new(reason, event.as_token_event()));
+            match event {
+                DelegationEvent::Approve(approval) => {
+                    if let Some(index) = live.take() {
+                        delegations[index].end = end(DelegationEndReason::Replaced);
+                    }
+                    reset_by_owner_change = None;
+                    live = Some(delegations.len());
+                    delegations.push(Delegation::new(approval));
+                }
+                DelegationEvent::Revoke(_) => {
+                    if let Some(index) = live.take() {
+                        delegations[index].end = end(DelegationEndReason::Revoked);
+                    }
+                    reset_by_owner_change = None;
+                }
+                DelegationEvent::OwnerChange(_) => {
+                    if let Some(index) = live.take() {
+                        delegations[index].end = end(DelegationEndReason::OwnerChanged);
+                        reset_by_owner_change = Some(index);
+                    }
+                }
+                DelegationEvent::Transfer(transfer) => {
+                    if let Some(index) = Self::delegation_used_by(
+                        &mut delegations,
+                        &mut live,
+                        &mut reset_by_owner_change,
+                        &transfer.signer,
+                    ) {
+                        let delegation = &mut delegations[index];
+                        delegation.transfers.push(transfer.clone());
+                        delegation.use_amount(transfer.amount, transfer);
+                        if delegation.end.is_some() {
+                            live = None;
+                        }
+                    }
+                }
+                DelegationEvent::Burn(burn) => {
+                    if let Some(index) = Self::delegation_used_by(
+                        &mut delegations,
+                        &mut live,
+                        &mut reset_by_owner_change,
+                        &burn.signer,
+                    ) {
+                        let delegation = &mut delegations[index];
+                        delegation.burns.push(burn.clone());
+                        delegation.use_amount(burn.amount, burn);
+                        if delegation.end.is_some() {
+                            live = None;
+                        }
+                    }
+                }
+            }
+        }
+        delegations
+    }
+
+    /// All delegations approved by a previous owner which were still around
+    /// when the account changed hands, for each ownership period
+    pub fn inherited_delegations<'a>(
+        &self,
+        owner_periods: &[OwnerPeriod],
+        delegations: &'a [Delegation],
+    ) -> Vec<InheritedDelegation<'a>> {
+        let mut inherited_delegations = vec![];
+        for (period_index, owner_period) in owner_periods.iter().enumerate() {
+            let owner_change = match &owner_period.start {
+                Some(owner_change) => owner_change,
+                None => continue,
+            };
+            let is_current_period = period_index == owner_periods.len() - 1;
+            for delegation in delegations {
+                let delegate_change = &delegation.approval;
+                // only delegations from earlier periods, which were live at the owner change
+                if delegate_change.order() > owner_change.order()
+                    || delegation.ended_before(owner_change.order())
+                {
+                    continue;
+                }
+                // delegations from the period's own owner aren't harmful
+                if delegate_change.signer == owner_period.owner
+                    || (is_current_period && self.is_current_owner(&delegate_change.signer))
+                {
+                    continue;
+                }
+                inherited_delegations.push(InheritedDelegation {
+                    period_index,
+                    delegation,
+                    used_transaction_ids: delegation
+                        .used_transaction_ids_between(owner_change.order(), owner_period.end),
+                });
+            }
+        }
+        inherited_delegations
+    }
+
+    /// Raw amount which inherited delegations can still take from the
+    /// account right now, bounded by its current balance
+    pub fn amount_at_risk(&self) -> u64 {
+        let owner_periods = self.owner_periods();
+        let delegations = self.delegation_timeline();
+        let current_period_index = owner_periods.len() - 1;
+        self.inherited_delegations(&owner_periods, &delegations)
+            .iter()
+            .filter(|inherited_delegation| {
+                inherited_delegation.period_index == current_period_index
+                    && inherited_delegation
+                        .delegation
+                        .is_live(self.current_delegate)
+            })
+            .map(|inherited_delegation| inherited_delegation.delegation.remaining_amount)
+            .max()
+            .unwrap_or(0)
+            .min(self.current_amount)
+    }
+
+    /// The close authority, if it was last set by someone other than the
+    /// current owner to someone other than the current owner
+    pub fn foreign_close_authority(&self) -> Option<Pubkey> {
+        let close_authority_change = self.latest_close_authority_change()?;
+        if self.is_current_owner(&close_authority_change.signer) {
+            return None;
+        }
+        close_authority_change
+            .new_close_authority
+            .filter(|close_authority| !self.is_current_owner(close_authority))
+    }
+
+    /// Record the balance change of a transaction on its transfers and burns
+    pub fn set_balance_change(&mut self, transaction_id: &Signature, balance_change: Option<i128>) {
+        for transfer in &mut self.possible_delegate_transfers {
+            if transfer.transaction_id == *transaction_id {
+                transfer.balance_change = balance_change;
+            }
+        }
+        for burn in &mut self.possible_delegate_burns {
+            if burn.transaction_id == *transaction_id {
+                burn.balance_change = balance_change;
+            }
+        }
+    }
+
+    /// Sum of the balance decreases in all transactions where an inherited
+    /// delegation was used. Each transaction counts once, even if it moved
+    /// funds with several instructions.
+    pub fn loss(&self) -> Loss {
+        let owner_periods = self.owner_periods();
+        let delegations = self.delegation_timeline();
+        let mut transaction_ids = self
+            .inherited_delegations(&owner_periods, &delegations)
+            .into_iter()
+            .flat_map(|inherited_delegation| inherited_delegation.used_transaction_ids)
+            .collect::<Vec<_>>();
+        transaction_ids.sort();
+        transaction_ids.dedup();
+
+        let balance_changes = self
+            .possible_delegate_transfers
+            .iter()
+            .map(|transfer| (transfer.transaction_id, transfer.balance_change))
+            .chain(
+                self.possible_delegate_burns
+                    .iter()
+                    .map(|burn| (burn.transaction_id, burn.balance_change)),
+            )
+            .collect::<std::collections::HashMap<_, _>>();
+
+        let mut loss = Loss::default();
+        for transaction_id in transaction_ids {
+            match balance_changes.get(&transaction_id).copied().flatten() {
+                Some(balance_change) => {
+                    if balance_change < 0 {
+                        loss.amount = loss
+                            .amount
+                            .saturating_add(balance_change.unsigned_abs() as u64);
+                    }
+                }
+                None => loss.unquantified_transaction_ids.push(transaction_id),
+            }
+            loss.transaction_ids.push(transaction_id);
+        }
+        loss
+    }
+
+    fn delegation_used_by(
+        delegations: &mut [Delegation],
+        live: &mut Option<usize>,
+        reset_by_owner_change: &mut Option<usize>,
+        signer: &Pubkey,
+    ) -> Option<usize> {
+        if let Some(index) = *live {
+            return Some(index)
+                .filter(|index| delegations[*index].approval.new_delegate == *signer);
+        }
+        let index = reset_by_owner_change
+            .filter(|index| delegations[*index].approval.new_delegate == *signer)?;
+        // the delegate was able to sign after the owner change, so it wasn't reset
+        delegations[index].end = None;
+        *live = Some(index);
+        *reset_by_owner_change = None;
+        Some(index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_delegate_change(slot: Slot, new_delegate: Pubkey, amount: u64) -> DelegateChange {
+        DelegateChange {
+            slot,
+            new_delegate,
+            amount,
+            ..DelegateChange::default()
+        }
+    }
+
+    fn new_delegate_transfer(slot: Slot, signer: Pubkey, amount: u64) -> DelegateTransfer {
+        DelegateTransfer {
+            slot,
+            signer,
+            amount,
+            ..DelegateTransfer::default()
+        }
+    }
+
+    #[test]
+    fn loss_from_balance_changes() {
+        let owner = Pubkey::new_unique();
+        let delegate = Pubkey::new_unique();
+        let mut entry = TokenAccountEntry::new(owner, Pubkey::new_unique());
+        entry.owner_changes.push(OwnerChange {
+            slot: 10,
+            signer: Pubkey::new_unique(),
+            new_owner: owner,
+            ..OwnerChange::default()
+        });
+        entry.delegate_changes.push(DelegateChange {
+            signer: entry.owner_changes[0].signer,
+            ..new_delegate_change(9, delegate, 100)
+        });
+        // two instructions in the same transaction only count once
+        for (slot, instruction_index, transaction_id) in [
+            (11, 0, Signature::new(&[1; 64])),
+            (11, 1, Signature::new(&[1; 64])),
+            (12, 0, Signature::new(&[2; 64])),
+        ] {
+            entry.possible_delegate_transfers.push(DelegateTransfer {
+                instruction_index,
+                transaction_id,
+                ..new_delegate_transfer(slot, delegate, 10)
+            });
+        }
+        entry.set_balance_change(&Signature::new(&[1; 64]), Some(-20));
+
+        let loss = entry.loss();
+        assert_eq!(loss.amount, 20);
+        assert_eq!(loss.transaction_ids.len(), 2);
+        assert_eq!(
+            loss.unquantified_transaction_ids,
+            vec![Signature::new(&[2; 64])]
+        );
+    }
+
+    #[test]
+    fn delegation_timeline_depleted() {
+        let delegate = Pubkey::new_unique();
+        let mut entry = TokenAccountEntry::new(Pubkey::new_unique(), Pubkey::new_unique());
+        entry
+            .delegate_changes
+            .push(new_delegate_change(10, delegate, 10));
+        entry
+            .possible_delegate_transfers
+            .push(new_delegate_transfer(12, delegate, 4));
+        entry
+            .possible_delegate_transfers
+            .push(new_delegate_transfer(11, delegate, 6));
+        // only the delegate can deplete the delegation
+        entry
+            .possible_delegate_transfers
+            .push(new_delegate_transfer(11, Pubkey::new_unique(), 6));
+
+        let timeline = entry.delegation_timeline();
+        assert_eq!(timeline.len(), 1);
+        assert_eq!(timeline[0].transfers.len(), 2);
+        assert_eq!(timeline[0].remaining_amount, 0);
+        let end = timeline[0].end.as_ref().unwrap();
+        assert_eq!(end.reason, DelegationEndReason::Depleted);
+        assert_eq!(end.slot, 12);
+        assert!(!timeline[0].is_live(None));
+    }
+
+    #[test]
+    fn delegation_timeline_replaced_and_revoked() {
+        let mut entry = TokenAccountEntry::new(Pubkey::new_unique(), Pubkey::new_unique());
+        entry
+            .delegate_changes
+            .push(new_delegate_change(10, Pubkey::new_unique(), 10));
+        entry
+            .delegate_changes
+            .push(new_delegate_change(11, Pubkey::new_unique(), 10));
+        entry.delegate_revokes.push(DelegateRevoke {
+            slot: 12,
+            ..DelegateRevoke::default()
+        });
+
+        let timeline = entry.delegation_timeline();
+        assert_eq!(timeline.len(), 2);
+        assert_eq!(
+            timeline[0].end.as_ref().unwrap().reason,
+            DelegationEndReason::Replaced
+        );
+        assert_eq!(
+            timeline[1].end.as_ref().unwrap().reason,
+            DelegationEndReason::Revoked
+        );
+        assert!(timeline[1].ended_before((13, 0, 0)));
+        assert!(!timeline[1].ended_before((12, 0, 0)));
+    }
+
+    #[test]
+    fn delegation_timeline_owner_change() {
+        let delegate = Pubkey::new_unique();
+        let mut entry = TokenAccountEntry::new(Pubkey::new_unique(), Pubkey::new_unique());
+        entry
+            .delegate_changes
+            .push(new_delegate_change(10, delegate, 10));
+        entry.owner_changes.push(OwnerChange {
+            slot: 11,
+            ..OwnerChange::default()
+        });
+
+        // the owner change is assumed to reset the delegate...
+        let timeline = entry.delegation_timeline();
+        assert_eq!(
+            timeline[0].end.as_ref().unwrap().reason,
+            DelegationEndReason::OwnerChanged
+        );
+        assert!(!timeline[0].is_live(None));
+        // ...unless the account still has it
+        assert!(timeline[0].is_live(Some(delegate)));
+
+        // ...or the delegate was able to use it afterwards
+        entry
+            .possible_delegate_transfers
+            .push(new_delegate_transfer(12, delegate, 4));
+        let timeline = entry.delegation_timeline();
+        assert!(timeline[0].end.is_none());
+        assert_eq!(timeline[0].remaining_amount, 6);
+        assert_eq!(
+            timeline[0]
+                .used_transaction_ids_between((11, 0, 0), None)
+                .len(),
+            1
+        );
+        assert!(timeline[0]
+            .used_transaction_ids_between((11, 0, 0), Some((12, 0, 0)))
+            .is_empty());
+    }
+
+    #[test]
+    fn delegation_timeline_intra_slot_order() {
+        let delegate = Pubkey::new_unique();
+        let mut entry = TokenAccountEntry::new(Pubkey::new_unique(), Pubkey::new_unique());
+        let approval = new_delegate_change(10, delegate, 10);
+        let revoke = DelegateRevoke {
+            slot: 10,
+            transaction_id: Signature::new(&[1; 64]),
+            ..DelegateRevoke::default()
+        };
+        entry.delegate_changes.push(approval.clone());
+        entry.delegate_revokes.push(revoke.clone());
+        assert_eq!(entry.slots_with_unordered_transactions(), vec![10]);
+
+        // the revoke came first in the block, so the approval is still live
+        let transaction_indexes = vec![(revoke.transaction_id, 3), (approval.transaction_id, 7)]
+            .into_iter()
+            .collect();
+        entry.set_transaction_indexes(10, &transaction_indexes);
+        let timeline = entry.delegation_timeline();
+        assert!(timeline[0].end.is_none());
+
+        let transaction_indexes = vec![(revoke.transaction_id, 7), (approval.transaction_id, 3)]
+            .into_iter()
+            .collect();
+        entry.set_transaction_indexes(10, &transaction_indexes);
+        let timeline = entry.delegation_timeline();
+        assert_eq!(
+            timeline[0].end.as_ref().unwrap().reason,
+            DelegationEndReason::Revoked
+        );
+    }
 }